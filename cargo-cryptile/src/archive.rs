@@ -0,0 +1,822 @@
+//! Encrypted archives:
+//! a whole directory tree (paths, permissions, modification times and
+//! contents) packed into a single encrypted file, so that the names,
+//! sizes and structure of the files aren't visible from outside.
+//!
+//! The plaintext of an archive is a sequence of member records:
+//! kind (1) | path length (2) | path | mode (4) | mtime (8) | size (8) | data (size)
+//!
+//! All numbers are little endian. Paths are relative and `/` separated.
+//! The data of a symbolic link is its target. As the encrypted stream is
+//! authenticated, the end of the last record is the end of the archive.
+//!
+//! Members are read from disk and parsed back while streaming through the
+//! encryption pipeline, so archives are never loaded into memory.
+//!
+//! # Example
+//! ```no_run
+//! use cargo_cryptile::archive;
+//! use hmac_sha256::Hash;
+//!
+//! let key = Hash::hash(b"password");
+//!
+//! archive::create(&["photos"], "photos.cryptile", &key).unwrap();
+//! for member in archive::list("photos.cryptile", &key).unwrap() {
+//!     println!("{}", member.path);
+//! }
+//! archive::extract("photos.cryptile", &key, "restored", &[]).unwrap();
+//! ```
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::metadata::{mode_of, set_mode, PERMISSION_BITS};
+use super::{decrypt_to, FileInfo, FileOptions, delete, encrypt_to, inspect, is_correct_key, FILE_EXTENSION, FLAG_ARCHIVE, UNKNOWN_SIZE};
+
+const KIND_FILE: u8 = 0;
+const KIND_DIR: u8 = 1;
+const KIND_SYMLINK: u8 = 2;
+
+/// Size of a record before its path and its data
+const RECORD_PREFIX_SIZE: usize = 3;
+const RECORD_SUFFIX_SIZE: usize = 20;
+
+/// Type of an archive member
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MemberKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// A file, directory or symbolic link stored in an archive
+#[derive(Clone, Debug)]
+pub struct Member {
+    /// Relative, `/` separated path inside the archive
+    pub path: String,
+    pub kind: MemberKind,
+    /// Unix permission bits
+    pub mode: u32,
+    /// Modification time as seconds since the unix epoch
+    pub mtime: i64,
+    /// Size of the content (the length of the target for symbolic links)
+    pub size: u64,
+}
+
+impl Member {
+    fn record_header(&self) -> Vec<u8> {
+        let kind = match self.kind {
+            MemberKind::File => KIND_FILE,
+            MemberKind::Dir => KIND_DIR,
+            MemberKind::Symlink => KIND_SYMLINK,
+        };
+        let mut bytes = Vec::with_capacity(RECORD_PREFIX_SIZE + self.path.len() + RECORD_SUFFIX_SIZE);
+        bytes.push(kind);
+        bytes.extend_from_slice(&(self.path.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(self.path.as_bytes());
+        bytes.extend_from_slice(&self.mode.to_le_bytes());
+        bytes.extend_from_slice(&self.mtime.to_le_bytes());
+        bytes.extend_from_slice(&self.size.to_le_bytes());
+        bytes
+    }
+
+    /// Whether the member is one of `paths` or inside one of them
+    fn selected_by(&self, paths: &[&str]) -> bool {
+        paths.is_empty() || paths.iter().any(|p| {
+            let p = p.trim_end_matches('/');
+            self.path == p || self.path.starts_with(&format!("{}/", p))
+        })
+    }
+}
+
+fn corrupted() -> Error {
+    Error::new(ErrorKind::InvalidData, "corrupted archive")
+}
+
+/// Checks that a member path stays inside the extraction directory
+fn validate_path(path: &str) -> Result<(), Error> {
+    let valid = !path.is_empty()
+        && path.split('/').all(|c| !c.is_empty() && c != "." && c != ".." && !c.contains(['\\', ':']));
+    if !valid {
+        return Err(Error::new(ErrorKind::InvalidData, format!("unsafe path in archive: {}", path)))
+    }
+    Ok(())
+}
+
+fn mtime_of(metadata: &fs::Metadata) -> i64 {
+    match metadata.modified() {
+        Ok(t) => match t.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        },
+        Err(_) => 0,
+    }
+}
+
+fn to_system_time(mtime: i64) -> SystemTime {
+    if mtime >= 0 {
+        UNIX_EPOCH + Duration::from_secs(mtime as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(mtime.unsigned_abs())
+    }
+}
+
+/// Collects the members for a path given on the command line,
+/// stored under its own name, along with the path to read each one from.
+/// Special files (fifos, sockets, devices) are left out
+fn collect(path: &Path, members: &mut VecDeque<(PathBuf, Member)>) -> Result<(), Error> {
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(n) => n.to_owned(),
+        None => return Err(Error::new(ErrorKind::InvalidInput, format!("invalid path: {}", path.display()))),
+    };
+    collect_as(path, name, members)
+}
+
+fn collect_as(path: &Path, name: String, members: &mut VecDeque<(PathBuf, Member)>) -> Result<(), Error> {
+    let metadata = fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+
+    let (kind, size) = if file_type.is_symlink() {
+        let target = fs::read_link(path)?;
+        let target = target.to_str()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "link target is not valid UTF-8"))?;
+        (MemberKind::Symlink, target.len() as u64)
+    } else if file_type.is_dir() {
+        (MemberKind::Dir, 0)
+    } else if file_type.is_file() {
+        (MemberKind::File, metadata.len())
+    } else {
+        return Ok(())
+    };
+
+    if name.len() > u16::MAX as usize {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("path too long: {}", name)))
+    }
+    members.push_back((path.to_owned(), Member {
+        path: name.clone(),
+        kind,
+        mode: mode_of(&metadata),
+        mtime: mtime_of(&metadata),
+        size,
+    }));
+
+    if kind == MemberKind::Dir {
+        let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>, Error>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let child = match entry.file_name().to_str() {
+                Some(c) => format!("{}/{}", name, c),
+                None => return Err(Error::new(ErrorKind::InvalidData,
+                    format!("path is not valid UTF-8: {}", entry.path().display()))),
+            };
+            collect_as(&entry.path(), child, members)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reader producing the records of the given members,
+/// opening each file only when its content is reached
+struct MemberReader {
+    members: VecDeque<(PathBuf, Member)>,
+    pending: io::Cursor<Vec<u8>>,
+    content: Option<(io::Take<File>, u64)>,
+}
+
+impl MemberReader {
+    fn new(members: VecDeque<(PathBuf, Member)>) -> MemberReader {
+        MemberReader { members, pending: io::Cursor::new(Vec::new()), content: None }
+    }
+
+    /// Moves on to the next member. Returns false when there are no members left
+    fn next_member(&mut self) -> Result<bool, Error> {
+        let (path, member) = match self.members.pop_front() {
+            Some(m) => m,
+            None => return Ok(false),
+        };
+
+        let mut record = member.record_header();
+        match member.kind {
+            MemberKind::File => {
+                let file = File::open(&path)?;
+                self.content = Some((file.take(member.size), member.size));
+            }
+            MemberKind::Symlink => {
+                let target = fs::read_link(&path)?;
+                let target = target.to_string_lossy();
+                if target.len() as u64 != member.size {
+                    return Err(Error::new(ErrorKind::InvalidData,
+                        format!("link changed while archiving: {}", path.display())))
+                }
+                record.extend_from_slice(target.as_bytes());
+            }
+            MemberKind::Dir => (),
+        }
+        self.pending = io::Cursor::new(record);
+        Ok(true)
+    }
+}
+
+impl Read for MemberReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.pending.read(buf)?;
+            if n > 0 {
+                return Ok(n)
+            }
+            if let Some((content, remaining)) = self.content.as_mut() {
+                let n = content.read(buf)?;
+                if n > 0 {
+                    *remaining -= n as u64;
+                    return Ok(n)
+                }
+                if *remaining > 0 {
+                    return Err(Error::new(ErrorKind::InvalidData, "file shrunk while archiving"))
+                }
+                self.content = None;
+            }
+            if !self.next_member()? {
+                return Ok(0)
+            }
+        }
+    }
+}
+
+/// Receives the members of an archive while it is being parsed
+trait Visitor {
+    fn member(&mut self, member: &Member) -> Result<(), Error>;
+    fn data(&mut self, data: &[u8]) -> Result<(), Error>;
+    fn end(&mut self) -> Result<(), Error>;
+}
+
+enum State {
+    Prefix,
+    Path { kind: u8 },
+    Suffix { kind: u8, path: String },
+    Data { remaining: u64 },
+}
+
+/// Writer parsing the plaintext of an archive as it is decrypted
+struct Parser<V: Visitor> {
+    visitor: V,
+    state: State,
+    buffer: Vec<u8>,
+    needed: usize,
+}
+
+impl<V: Visitor> Parser<V> {
+    fn new(visitor: V) -> Parser<V> {
+        Parser { visitor, state: State::Prefix, buffer: Vec::new(), needed: RECORD_PREFIX_SIZE }
+    }
+
+    /// Checks that the archive didn't end in the middle of a record
+    fn finish(self) -> Result<V, Error> {
+        match self.state {
+            State::Prefix if self.buffer.is_empty() => Ok(self.visitor),
+            _ => Err(corrupted()),
+        }
+    }
+
+    fn advance(&mut self) -> Result<(), Error> {
+        let bytes = std::mem::take(&mut self.buffer);
+        let state = std::mem::replace(&mut self.state, State::Prefix);
+
+        match state {
+            State::Prefix => {
+                let kind = bytes[0];
+                if kind > KIND_SYMLINK {
+                    return Err(corrupted())
+                }
+                self.needed = u16::from_le_bytes([bytes[1], bytes[2]]) as usize;
+                self.state = State::Path { kind };
+            }
+            State::Path { kind } => {
+                let path = String::from_utf8(bytes).map_err(|_| corrupted())?;
+                validate_path(&path)?;
+                self.needed = RECORD_SUFFIX_SIZE;
+                self.state = State::Suffix { kind, path };
+            }
+            State::Suffix { kind, path } => {
+                let member = Member {
+                    path,
+                    kind: match kind {
+                        KIND_FILE => MemberKind::File,
+                        KIND_DIR => MemberKind::Dir,
+                        _ => MemberKind::Symlink,
+                    },
+                    mode: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+                    mtime: i64::from_le_bytes(bytes[4..12].try_into().unwrap()),
+                    size: u64::from_le_bytes(bytes[12..20].try_into().unwrap()),
+                };
+                if member.kind == MemberKind::Dir && member.size != 0 {
+                    return Err(corrupted())
+                }
+                self.visitor.member(&member)?;
+                self.end_member_if_empty(member.size)?;
+            }
+            State::Data { .. } => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn end_member_if_empty(&mut self, remaining: u64) -> Result<(), Error> {
+        if remaining == 0 {
+            self.visitor.end()?;
+            self.needed = RECORD_PREFIX_SIZE;
+            self.state = State::Prefix;
+        } else {
+            self.state = State::Data { remaining };
+        }
+        Ok(())
+    }
+}
+
+impl<V: Visitor> Write for Parser<V> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let written = buf.len();
+        while !buf.is_empty() {
+            if let State::Data { remaining } = self.state {
+                let n = (remaining.min(buf.len() as u64)) as usize;
+                self.visitor.data(&buf[..n])?;
+                buf = &buf[n..];
+                self.end_member_if_empty(remaining - n as u64)?;
+                continue
+            }
+
+            let n = (self.needed - self.buffer.len()).min(buf.len());
+            self.buffer.extend_from_slice(&buf[..n]);
+            buf = &buf[n..];
+            if self.buffer.len() == self.needed {
+                self.advance()?;
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Lister {
+    members: Vec<Member>,
+}
+
+impl Visitor for Lister {
+    fn member(&mut self, member: &Member) -> Result<(), Error> {
+        self.members.push(member.clone());
+        Ok(())
+    }
+
+    fn data(&mut self, _: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn end(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+struct Extractor<'a> {
+    root: PathBuf,
+    selected: &'a [&'a str],
+    extracted: Vec<Member>,
+    current: Option<(Member, PathBuf)>,
+    file: Option<BufWriter<File>>,
+    link_target: Vec<u8>,
+    /// Directory attributes are restored last, as extracting
+    /// their content would change their modification time
+    dirs: Vec<(PathBuf, Member)>,
+}
+
+impl Extractor<'_> {
+    /// Path to extract a member to. Refuses paths going through symbolic
+    /// links, which an archive could use to write outside the root
+    fn target(&self, member: &Member) -> Result<PathBuf, Error> {
+        let mut target = self.root.clone();
+        let components: Vec<&str> = member.path.split('/').collect();
+        for (i, component) in components.iter().enumerate() {
+            target.push(component);
+            if i + 1 == components.len() {
+                break
+            }
+            match fs::symlink_metadata(&target) {
+                Ok(m) if m.file_type().is_symlink() => {
+                    return Err(Error::new(ErrorKind::InvalidData,
+                        format!("refusing to extract through a symbolic link: {}", member.path)))
+                }
+                Ok(m) if !m.is_dir() => {
+                    return Err(Error::new(ErrorKind::AlreadyExists,
+                        format!("not a directory: {}", target.display())))
+                }
+                Ok(_) => (),
+                Err(_) => fs::create_dir_all(&target)?,
+            }
+        }
+        Ok(target)
+    }
+}
+
+impl Visitor for Extractor<'_> {
+    fn member(&mut self, member: &Member) -> Result<(), Error> {
+        if !member.selected_by(self.selected) {
+            return Ok(())
+        }
+        let target = self.target(member)?;
+
+        // Never write through an existing link
+        if let Ok(m) = fs::symlink_metadata(&target) {
+            if m.file_type().is_symlink() || (m.is_file() && member.kind != MemberKind::File) {
+                fs::remove_file(&target)?;
+            }
+        }
+
+        match member.kind {
+            MemberKind::File => self.file = Some(BufWriter::new(File::create(&target)?)),
+            MemberKind::Dir => fs::create_dir_all(&target)?,
+            MemberKind::Symlink => self.link_target.clear(),
+        }
+        self.current = Some((member.clone(), target));
+        Ok(())
+    }
+
+    fn data(&mut self, data: &[u8]) -> Result<(), Error> {
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(data)?;
+        } else if self.current.is_some() {
+            self.link_target.extend_from_slice(data);
+        }
+        Ok(())
+    }
+
+    fn end(&mut self) -> Result<(), Error> {
+        let (member, target) = match self.current.take() {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+
+        match member.kind {
+            MemberKind::File => {
+                let file = self.file.take().unwrap().into_inner().map_err(|e| e.into_error())?;
+                file.set_modified(to_system_time(member.mtime))?;
+                set_mode(&target, member.mode & PERMISSION_BITS)?;
+            }
+            MemberKind::Dir => self.dirs.push((target, member.clone())),
+            MemberKind::Symlink => {
+                let link = String::from_utf8(std::mem::take(&mut self.link_target))
+                    .map_err(|_| corrupted())?;
+                make_symlink(&link, &target)?;
+            }
+        }
+        self.extracted.push(member);
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn make_symlink(link: &str, target: &Path) -> Result<(), Error> {
+    std::os::unix::fs::symlink(link, target)
+}
+
+#[cfg(not(unix))]
+fn make_symlink(_: &str, target: &Path) -> Result<(), Error> {
+    Err(Error::new(ErrorKind::Unsupported,
+        format!("symbolic links can't be extracted on this platform: {}", target.display())))
+}
+
+/// Re-serializes the records of an archive, leaving out the replaced members
+struct Copier<W: Write> {
+    writer: W,
+    replaced: Vec<String>,
+    skipping: bool,
+}
+
+impl<W: Write> Visitor for Copier<W> {
+    fn member(&mut self, member: &Member) -> Result<(), Error> {
+        let replaced: Vec<&str> = self.replaced.iter().map(|r| r.as_str()).collect();
+        self.skipping = member.selected_by(&replaced);
+        if !self.skipping {
+            self.writer.write_all(&member.record_header())?;
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, data: &[u8]) -> Result<(), Error> {
+        if !self.skipping {
+            self.writer.write_all(data)?;
+        }
+        Ok(())
+    }
+
+    fn end(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Sending half of an in-memory pipe between two threads
+struct PipeWriter(SyncSender<Vec<u8>>);
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf.to_vec()).map_err(|_| Error::from(ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Receiving half of an in-memory pipe, ending when the writer is dropped
+struct PipeReader {
+    receiver: Receiver<Vec<u8>>,
+    current: io::Cursor<Vec<u8>>,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n)
+            }
+            match self.receiver.recv() {
+                Ok(data) => self.current = io::Cursor::new(data),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+fn check_archive(archive: &str) -> Result<FileInfo, Error> {
+    let info = inspect(archive)?;
+    if !info.archive {
+        return Err(Error::new(ErrorKind::Unsupported, "not an encrypted archive"))
+    }
+    Ok(info)
+}
+
+fn collect_all(paths: &[&str]) -> Result<VecDeque<(PathBuf, Member)>, Error> {
+    let mut members = VecDeque::new();
+    for path in paths {
+        collect(Path::new(path), &mut members)?;
+    }
+    Ok(members)
+}
+
+/// Writes `members` and then whatever `previous` reads to a new archive
+/// next to `archive`, which replaces it once `finished` succeeds
+fn write_archive<R: Read>(archive: &str, key: &[u8; 32], options: &FileOptions, previous: Option<R>,
+    members: VecDeque<(PathBuf, Member)>, finished: impl FnOnce() -> Result<(), Error>) -> Result<(), Error>
+{
+    let temp = format!("{}.tmp", archive);
+    let result = File::create(&temp).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        let mut reader: Box<dyn Read> = match previous {
+            Some(previous) => Box::new(previous.chain(MemberReader::new(members))),
+            None => Box::new(MemberReader::new(members)),
+        };
+        encrypt_to(&mut reader, &mut writer, key, UNKNOWN_SIZE, FLAG_ARCHIVE, options)?;
+        writer.flush()
+    });
+
+    match result.and_then(|_| finished()).and_then(|_| fs::rename(&temp, archive)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            delete(&temp);
+            Err(e)
+        }
+    }
+}
+
+/// Function to create an encrypted archive from files and directories.
+/// Each path is stored under its own name, along with everything inside it.
+/// Special files (fifos, sockets, devices) are left out
+/// Returns the members stored
+///
+/// # Errors
+/// This function will return an appropriate variant of
+/// `std::io::Error` if there is any error reading the files
+/// or creating the archive.
+/// 
+/// If the name of the archive doesn't end with .cryptile,
+/// It will give a `std::io::ErrorKind::Unsupported` error.
+pub fn create(paths: &[&str], archive: &str, key: &[u8; 32]) -> Result<Vec<Member>, Error> {
+    create_with(paths, archive, key, &FileOptions::default())
+}
+
+/// Function to create an encrypted archive like `create`, compressing
+/// and padding it and recording the use of a keyfile if `options` ask for it.
+/// The metadata options don't apply to archives
+/// Returns the members stored
+///
+/// # Errors
+/// This function gives the same errors as `create`
+pub fn create_with(paths: &[&str], archive: &str, key: &[u8; 32],
+    options: &FileOptions) -> Result<Vec<Member>, Error>
+{
+    if !archive.ends_with(FILE_EXTENSION) {
+        return Err(Error::from(ErrorKind::Unsupported))
+    }
+    let members = collect_all(paths)?;
+    let stored = members.iter().map(|(_, m)| m.clone()).collect();
+
+    write_archive::<io::Empty>(archive, key, options, None, members, || Ok(()))?;
+    Ok(stored)
+}
+
+/// Function to list the members of an encrypted archive
+///
+/// # Errors
+/// This function gives the same errors as `decrypt`.
+///
+/// If the file isn't an archive,
+/// It will give a `std::io::ErrorKind::Unsupported` error.
+pub fn list(archive: &str, key: &[u8; 32]) -> Result<Vec<Member>, Error> {
+    check_archive(archive)?;
+
+    let mut parser = Parser::new(Lister { members: Vec::new() });
+    decrypt_to(archive, key, &mut BufWriter::new(&mut parser))?;
+    Ok(parser.finish()?.members)
+}
+
+/// Function to extract the members of an encrypted archive into a directory,
+/// restoring their permissions and modification times.
+/// If `members` isn't empty, only the given members (and for directories,
+/// everything inside them) are extracted.
+/// Returns the members extracted
+///
+/// # Errors
+/// This function gives the same errors as `list`, and an appropriate variant
+/// of `std::io::Error` if there is any error creating the files.
+///
+/// If a member would be written outside of the directory,
+/// It will give a `std::io::ErrorKind::InvalidData` error.
+pub fn extract(archive: &str, key: &[u8; 32], dirname: &str, members: &[&str]) -> Result<Vec<Member>, Error> {
+    check_archive(archive)?;
+    fs::create_dir_all(dirname)?;
+
+    let mut parser = Parser::new(Extractor {
+        root: PathBuf::from(dirname),
+        selected: members,
+        extracted: Vec::new(),
+        current: None,
+        file: None,
+        link_target: Vec::new(),
+        dirs: Vec::new(),
+    });
+    decrypt_to(archive, key, &mut BufWriter::new(&mut parser))?;
+    let extractor = parser.finish()?;
+
+    for (path, member) in extractor.dirs.iter().rev() {
+        set_mode(path, member.mode & PERMISSION_BITS)?;
+        File::open(path)
+            .and_then(|d| d.set_modified(to_system_time(member.mtime)))
+            .ok();
+    }
+    Ok(extractor.extracted)
+}
+
+/// Function to add files and directories to an existing encrypted archive.
+/// Each path is stored under its own name, replacing any member
+/// with the same name (and everything inside it).
+/// The archive is rewritten while streaming, and only replaced
+/// once the new one is complete.
+/// Returns the members added
+///
+/// # Errors
+/// This function gives the same errors as `list` and `create`
+pub fn add(archive: &str, key: &[u8; 32], paths: &[&str]) -> Result<Vec<Member>, Error> {
+    let info = check_archive(archive)?;
+    if !is_correct_key(archive, key)? {
+        return Err(Error::from(ErrorKind::InvalidInput))
+    }
+
+    let members = collect_all(paths)?;
+    let added: Vec<Member> = members.iter().map(|(_, m)| m.clone()).collect();
+    let replaced = added.iter()
+        .filter(|m| !m.path.contains('/'))
+        .map(|m| m.path.clone())
+        .collect();
+
+    // The old archive is decrypted on another thread and streamed
+    // through a pipe into the encryption of the new one
+    let (sender, receiver) = mpsc::sync_channel(64);
+    let source = archive.to_owned();
+    let key_copy = *key;
+    let decryption = thread::spawn(move || -> Result<(), Error> {
+        let copier = Copier { writer: BufWriter::new(PipeWriter(sender)), replaced, skipping: false };
+        let mut parser = Parser::new(copier);
+        decrypt_to(&source, &key_copy, &mut BufWriter::new(&mut parser))?;
+        parser.finish()?.writer.flush()
+    });
+
+    let previous = PipeReader { receiver, current: io::Cursor::new(Vec::new()) };
+    let finished = || match decryption.join() {
+        Ok(result) => result,
+        Err(_) => Err(Error::other("archive decryption failed")),
+    };
+    // The new archive is written the way the old one was
+    let options = FileOptions {
+        compression: info.compression_level,
+        padding: info.padding,
+        keyfile: info.keyfile,
+        fingerprint: info.fingerprint.is_some(),
+        ..FileOptions::default()
+    };
+    write_archive(archive, key, &options, Some(previous), members, finished)?;
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hmac_sha256::Hash;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!("cryptile-archive-{}-{}", std::process::id(), name));
+        _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn archive_round_trip() {
+        let key = Hash::hash(b"archive key");
+        let root = temp_dir("round-trip");
+        let tree = root.join("tree");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::write(tree.join("a.txt"), b"first file").unwrap();
+        fs::write(tree.join("sub/b.bin"), vec![9u8; 100_000]).unwrap();
+        fs::write(root.join("extra.txt"), b"added later").unwrap();
+
+        let archive = root.join("tree.cryptile");
+        let archive = archive.to_str().unwrap();
+        create(&[tree.to_str().unwrap()], archive, &key).expect("Error in Creating");
+
+        let members = list(archive, &key).expect("Error in Listing");
+        let paths: Vec<&str> = members.iter().map(|m| m.path.as_str()).collect();
+        assert_eq!(paths, vec!["tree", "tree/a.txt", "tree/sub", "tree/sub/b.bin"]);
+        assert_eq!(members[3].size, 100_000);
+
+        add(archive, &key, &[root.join("extra.txt").to_str().unwrap()]).expect("Error in Adding");
+        assert_eq!(list(archive, &key).unwrap().len(), 5);
+
+        let out = root.join("out");
+        let extracted = extract(archive, &key, out.to_str().unwrap(), &["tree/sub"])
+            .expect("Error in Extracting");
+        assert_eq!(extracted.len(), 2);
+        assert_eq!(fs::read(out.join("tree/sub/b.bin")).unwrap(), vec![9u8; 100_000]);
+        assert!(!out.join("tree/a.txt").exists());
+
+        extract(archive, &key, out.to_str().unwrap(), &[]).expect("Error in Extracting");
+        assert_eq!(fs::read(out.join("extra.txt")).unwrap(), b"added later");
+
+        let wrong = Hash::hash(b"wrong key");
+        assert_eq!(list(archive, &wrong).err().unwrap().kind(), ErrorKind::InvalidInput);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_mode_bits_not_extracted() {
+        use std::os::unix::fs::PermissionsExt;
+        let key = Hash::hash(b"archive key");
+        let root = temp_dir("special-modes");
+        let tree = root.join("tree");
+        fs::create_dir_all(&tree).unwrap();
+        fs::write(tree.join("run.sh"), b"#!/bin/sh").unwrap();
+
+        // As a crafted archive could hold
+        let mut members = collect_all(&[tree.to_str().unwrap()]).unwrap();
+        members.iter_mut().for_each(|(_, m)| m.mode = 0o6755);
+        let archive = root.join("tree.cryptile");
+        let archive = archive.to_str().unwrap();
+        write_archive::<io::Empty>(archive, &key, &FileOptions::default(), None, members, || Ok(())).unwrap();
+
+        let out = root.join("out");
+        extract(archive, &key, out.to_str().unwrap(), &[]).expect("Error in Extracting");
+        for path in [out.join("tree"), out.join("tree/run.sh")] {
+            let mode = fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, 0o755);
+        }
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn rejects_unsafe_paths() {
+        assert!(validate_path("../etc/passwd").is_err());
+        assert!(validate_path("a//b").is_err());
+        assert!(validate_path("").is_err());
+        assert!(validate_path("a/./b").is_err());
+        assert!(validate_path("dir/file.txt").is_ok());
+    }
+}
@@ -1,59 +1,106 @@
-use directories::ProjectDirs;
+use cargo_cryptile::generate::Charset;
+use cargo_cryptile::{DirOptions, FileOptions, LinkPolicy, Padding, SpecialPolicy, DEFAULT_COMPRESSION_LEVEL};
 use hmac_sha256::Hash;
-use serde::Deserialize;
-use serde::Serialize;
+use std::env;
 use std::fs::{self, File};
-use std::io::Write;
-use std::path::PathBuf;
-use toml;
 
-fn config_path() -> Option<PathBuf> {
-    if let Some(proj_dirs) = ProjectDirs::from("com", "cryptile", "cryptile") {
-        let mut path = proj_dirs.config_dir().to_owned();
-        path.push(".saved.toml");
-        return Some(path);
-
-        // Linux:   /home/username/.config/cryptile/.saved.toml
-        // Windows: C:\Users\Username\AppData\Roaming\cryptile\cryptile\.saved.toml
-        // macOS:   /Users/Username/Library/Application Support/com.cryptile.cryptile/.saved.toml
-    }
-    None
-}
+use crate::keystore::{self, Conflict, Defaults, Key, Keystore};
 
 #[derive(PartialEq)]
 pub enum Pass<'a> {
     Saved { identifier: Option<&'a str> },
     Given { given: &'a str },
     Master,
+    /// No password option given, the password is asked for interactively
+    Prompt,
+    /// The password is read from a file, an environment variable or a file descriptor
+    Read { source: PassSource<'a> },
+    /// The master and saved passwords are tried in turn, then if `prompt`
+    /// is true and none of them is the key, the password is asked for
+    Auto { prompt: bool },
 }
 
-#[derive(Deserialize, Serialize)]
-struct Key {
-    key: [u8; 32],
-    identifier: String,
+/// Where a password is read from, for scripts which can't type it in
+#[derive(PartialEq, Clone, Copy)]
+pub enum PassSource<'a> {
+    File(&'a str),
+    Env(&'a str),
+    Fd(i32),
 }
 
-#[derive(Deserialize, Serialize)]
-struct SavedConfig {
-    master: Option<[u8; 32]>,
-    keys: Option<Vec<Key>>,
+impl<'a> PassSource<'a> {
+    fn parse(flag: &str, value: &'a str) -> Option<PassSource<'a>> {
+        match flag {
+            "--password-file" => Some(PassSource::File(value)),
+            "--password-env" => Some(PassSource::Env(value)),
+            "--password-fd" => value.parse().ok().filter(|fd| *fd >= 0).map(PassSource::Fd),
+            _ => None,
+        }
+    }
+
+    /// Reads the password: the first line of a file or file descriptor,
+    /// or the whole value of an environment variable
+    pub fn read(&self) -> Result<String, &'static str> {
+        let pass = match self {
+            PassSource::File(path) => match fs::read_to_string(path) {
+                Ok(s) => first_line(s),
+                Err(_) => return Err("Error reading the password file"),
+            },
+            PassSource::Env(var) => match env::var(var) {
+                Ok(s) => s,
+                Err(_) => return Err("The password environment variable is not set"),
+            },
+            PassSource::Fd(fd) => first_line(read_fd(*fd)?),
+        };
+        if pass.is_empty() {
+            return Err("The password read is empty");
+        }
+        Ok(pass)
+    }
 }
 
-impl SavedConfig {
-    fn search(&self, id: &str) -> Option<[u8; 32]> {
-        match self.keys
-                    .as_ref()
-                    .unwrap()
-                    .iter()
-                    .filter(|k| {
-                        (*k).identifier == id
-                    })
-                    .next()
-        {
-            None => None,
-            Some(k) => Some(k.key)
+fn first_line(mut s: String) -> String {
+    if let Some(end) = s.find('\n') {
+        s.truncate(end);
+    }
+    if s.ends_with('\r') {
+        s.pop();
+    }
+    s
+}
+
+#[cfg(unix)]
+fn read_fd(fd: i32) -> Result<String, &'static str> {
+    use std::io::{ErrorKind, Read};
+    use std::mem::ManuallyDrop;
+    use std::os::fd::FromRawFd;
+
+    // The descriptor is left open, and only read up to the end of the line
+    // one byte at a time, so that what follows (e.g. the data on stdin) is left to read
+    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        match file.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => line.push(byte[0]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => return Err("Error reading the password file descriptor"),
         }
     }
+    String::from_utf8(line).map_err(|_| "The password read is not valid UTF-8")
+}
+
+#[cfg(not(unix))]
+fn read_fd(_: i32) -> Result<String, &'static str> {
+    Err("Reading the password from a file descriptor isn't supported on this platform")
+}
+
+/// The key derived from a password
+pub fn key_of(pass: &str) -> [u8; 32] {
+    let pass: Vec<u8> = pass.bytes().collect();
+    Hash::hash(&pass)
 }
 
 #[derive(PartialEq)]
@@ -61,71 +108,171 @@ pub enum Operation<'a> {
     Encrypt,
     Decrypt,
     Set,
+    Info,
+    Verify,
+    Cat,
+    Edit,
+    Exec,
+    Ls,
+    Archive(ArchiveCommand),
+    Keys(KeysCommand),
+    Config(ConfigCommand),
+    KeyfileGenerate,
+    Generate(Generate<'a>),
     Help(&'a str),
 }
 
+#[derive(PartialEq, Clone, Copy)]
+pub enum ArchiveCommand {
+    Create,
+    List,
+    Extract,
+    Add,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum KeysCommand {
+    List,
+    Remove,
+    Rename,
+    Fingerprint,
+    Export,
+    Import,
+    Backup,
+    Restore,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum ConfigCommand {
+    Get,
+    Set,
+    Unset,
+}
+
+/// What `cryptile generate` makes
+#[derive(PartialEq, Clone, Copy)]
+pub enum Generate<'a> {
+    Password { length: usize, charset: Charset },
+    Passphrase { words: usize, separator: &'a str },
+}
+
+const DEFAULT_PASSWORD_LENGTH: usize = 20;
+const DEFAULT_PASSPHRASE_WORDS: usize = 6;
+
 pub struct Config<'a> {
     pub operation: Operation<'a>,
     file: Option<&'a str>,
     pub pass: Option<Pass<'a>>,
-    saved: Option<SavedConfig>,
+    /// Where `set` reads the password to save from, instead of prompting for it
+    source: Option<PassSource<'a>>,
+    /// Keyfile combined with the password (if any) into the key
+    keyfile: Option<&'a str>,
+    saved: Option<Keystore>,
     replace: bool,
+    force: bool,
+    command: Option<&'a [String]>,
+    recursive: bool,
+    dir: DirOptions<'a>,
+    /// Paths given to `archive`, or identifiers given to `keys`
+    paths: Vec<&'a str>,
+    /// What `keys import` does with identifiers which are already taken
+    on_conflict: Conflict,
+    allow_weak: bool,
 }
 
 fn get_pass<'a>(flag: &'a str, p: Option<&'a String>) -> Option<Pass<'a>> {
     match flag {
-        "-p" => {
-            if let None = p {
-                return None;
-            }
-            return Some(Pass::Given { given: p.unwrap() });
-        }
-        "-s" | "--saved" => {
-            if let None = p {
-                return None;
-            }
-            return Some(Pass::Saved {
-                identifier: Some(p.unwrap()),
-            });
-        }
-        "-m" | "--master" => return Some(Pass::Master),
-        _ => return None,
+        "-p" => Some(Pass::Given { given: p? }),
+        "-s" | "--saved" => Some(Pass::Saved { identifier: Some(p?) }),
+        "-m" | "--master" => Some(Pass::Master),
+        _ => PassSource::parse(flag, p?).map(|source| Pass::Read { source }),
     }
 }
 
-fn get_saved_pass() -> Result<SavedConfig, &'static str> {
-    let path = match config_path() {
-        Some(p) => p,
-        None => {
-            return Err("Error: No valid path for config file\
-                        could be retrieved from\
-                        the operating system")
-        }
-    };
-    if !path.exists() {
-        if let Err(_) = fs::create_dir_all(path.parent().unwrap()) {
-            return Err("Error creating config file");
-        }
+/// The password used along with the keyfile, if any. Without a keyfile,
+/// a password is needed, the default one if no option gives one
+fn key_pass<'a>(pass: Option<Pass<'a>>, keyfile: Option<&str>, defaults: &'a Defaults) -> Option<Pass<'a>> {
+    match (pass, keyfile) {
+        (None, Some(_)) => None,
+        (pass, _) => Some(pass.or_else(|| default_pass(defaults)).unwrap_or(Pass::Prompt)),
+    }
+}
 
-        if let Err(_) = File::create(&path) {
-            return Err("Error creating config file");
-        }
+/// Same as `key_pass`, except that with no option nor default password
+/// and `try_saved`, the saved passwords are tried before asking for one
+fn decrypt_pass<'a>(pass: Option<Pass<'a>>, keyfile: Option<&str>, defaults: &'a Defaults,
+    try_saved: bool) -> Option<Pass<'a>>
+{
+    match pass {
+        None if try_saved && keyfile.is_none() && defaults.password.is_none() => Some(Pass::Auto { prompt: true }),
+        pass => key_pass(pass, keyfile, defaults),
+    }
+}
+
+fn default_pass(defaults: &Defaults) -> Option<Pass<'_>> {
+    match defaults.password.as_deref()? {
+        "master" => Some(Pass::Master),
+        "prompt" => Some(Pass::Prompt),
+        saved => saved.strip_prefix("saved:").map(|id| Pass::Saved { identifier: Some(id) }),
     }
-    let config_file = match fs::read_to_string(path) {
-        Ok(s) => s,
-        Err(_) => return Err("Error Reading the config file"),
-    };
-    match toml::from_str(&config_file) {
-        Ok(s) => Ok(s),
-        Err(e) => {
-            eprintln!("{}", e);
-            return Err("Error parsing the Config File.");
+}
+
+fn parse_padding(value: Option<&str>) -> Option<Padding> {
+    match value {
+        Some("pow2") => Some(Padding::PowerOfTwo),
+        Some("padme") => Some(Padding::Padme),
+        _ => None,
+    }
+}
+
+/// Takes `--config <PATH>` and `--profile <NAME>` out of the arguments,
+/// as they may come anywhere before `--`, and chooses the keystore with them
+pub fn choose_keystore(args: &mut Vec<String>) -> Result<(), &'static str> {
+    let (mut config, mut profile) = (None, None);
+    let mut i = 0;
+    while i < args.len() && args[i] != "--" {
+        let slot = match args[i].as_str() {
+            "--config" => &mut config,
+            "--profile" => &mut profile,
+            _ => {
+                i += 1;
+                continue
+            }
+        };
+        match args.get(i + 1) {
+            Some(value) if slot.is_none() => *slot = Some(value.clone()),
+            _ => return Err(HELP_TEXT),
         }
+        args.drain(i..i + 2);
+    }
+    if config.is_some() && profile.is_some() {
+        return Err("Error: --config and --profile can't be used together");
     }
+    keystore::choose(config.as_deref(), profile.as_deref())
 }
 
 impl<'a> Config<'a> {
-    pub fn parse(args: &Vec<String>) -> Result<Config, &str> {
+    /// A configuration of `operation` without any option
+    fn new(operation: Operation<'a>) -> Config<'a> {
+        Config {
+            operation,
+            file: None,
+            pass: None,
+            source: None,
+            keyfile: None,
+            saved: None,
+            replace: false,
+            force: false,
+            command: None,
+            recursive: false,
+            dir: DirOptions::default(),
+            paths: Vec::new(),
+            on_conflict: Conflict::Fail,
+            allow_weak: false,
+        }
+    }
+
+    pub fn parse(args: &'a [String], defaults: &'a Defaults) -> Result<Config<'a>, &'a str> {
         let mut x = 0;
         for (i, arg) in args.iter().enumerate() {
             if arg == "cryptile" {
@@ -138,116 +285,573 @@ impl<'a> Config<'a> {
             "encrypt" => Operation::Encrypt,
             "decrypt" => Operation::Decrypt,
             "set" => Operation::Set,
+            "info" => Operation::Info,
+            "verify" => Operation::Verify,
+            "cat" => Operation::Cat,
+            "edit" => Operation::Edit,
+            "exec" => Operation::Exec,
+            "ls" => Operation::Ls,
+            "archive" => match args.get(x + 2).map(|a| a.as_str()) {
+                Some("create") => Operation::Archive(ArchiveCommand::Create),
+                Some("list") => Operation::Archive(ArchiveCommand::List),
+                Some("extract") => Operation::Archive(ArchiveCommand::Extract),
+                Some("add") => Operation::Archive(ArchiveCommand::Add),
+                _ => return Err(HELP_TEXT),
+            },
+            "keys" => match args.get(x + 2).map(|a| a.as_str()) {
+                Some("list") => Operation::Keys(KeysCommand::List),
+                Some("remove") => Operation::Keys(KeysCommand::Remove),
+                Some("rename") => Operation::Keys(KeysCommand::Rename),
+                Some("fingerprint") => Operation::Keys(KeysCommand::Fingerprint),
+                Some("export") => Operation::Keys(KeysCommand::Export),
+                Some("import") => Operation::Keys(KeysCommand::Import),
+                Some("backup") => Operation::Keys(KeysCommand::Backup),
+                Some("restore") => Operation::Keys(KeysCommand::Restore),
+                _ => return Err(HELP_TEXT),
+            },
+            "config" => match args.get(x + 2).map(|a| a.as_str()) {
+                Some("get") => Operation::Config(ConfigCommand::Get),
+                Some("set") => Operation::Config(ConfigCommand::Set),
+                Some("unset") => Operation::Config(ConfigCommand::Unset),
+                _ => return Err(HELP_TEXT),
+            },
+            "keyfile" if args.get(x + 2).map(|a| a.as_str()) == Some("generate") => {
+                Operation::KeyfileGenerate
+            }
+            "generate" => match args.get(x + 2).map(|a| a.as_str()) {
+                Some("passphrase") => Operation::Generate(Generate::Passphrase {
+                    words: DEFAULT_PASSPHRASE_WORDS,
+                    separator: "-",
+                }),
+                _ => Operation::Generate(Generate::Password {
+                    length: DEFAULT_PASSWORD_LENGTH,
+                    charset: Charset::Alphanumeric,
+                }),
+            },
             "--help" | "-h" => {
-                return Ok(Config {
-                    operation: Operation::Help(HELP_TEXT),
-                    file: None,
-                    pass: None,
-                    saved: None,
-                    replace: false,
-                })
+                return Ok(Config::new(Operation::Help(HELP_TEXT)))
             }
             _ => return Err(HELP_TEXT),
         };
 
-        if op == Operation::Encrypt || op == Operation::Decrypt {
-            if let None = args.get(x + 2) {
+        if op == Operation::Encrypt || op == Operation::Decrypt
+            || op == Operation::Verify || op == Operation::Cat || op == Operation::Edit
+            || op == Operation::Ls
+        {
+            let mut file = None;
+            let mut pass = None;
+            let mut replace = None;
+            let mut force = false;
+            let mut recursive = false;
+            let mut keyfile = None;
+            let mut dir = DirOptions::default();
+            let mut no_compress = false;
+            let mut no_pad = false;
+
+            let mut i = x + 2;
+            while i < args.len() {
+                let value = args.get(i + 1).map(|v| v.as_str());
+                match args[i].as_str() {
+                    "--replace" => replace = Some(true),
+                    "--no-replace" => replace = Some(false),
+                    "--no-compress" => no_compress = true,
+                    "--no-pad" => no_pad = true,
+                    "--force" => force = true,
+                    "-r" | "--recursive" => recursive = true,
+                    "--no-metadata" => dir.file.metadata = false,
+                    "--xattrs" => dir.file.xattrs = true,
+                    "--fingerprint" => dir.file.fingerprint = true,
+                    "--auto" => pass = Some(Pass::Auto { prompt: false }),
+                    "--obfuscate" => dir.file.obfuscate = true,
+                    "--compress" => {
+                        dir.file.compression = dir.file.compression.or(Some(DEFAULT_COMPRESSION_LEVEL));
+                    }
+                    "--pad" => {
+                        dir.file.padding = match parse_padding(value) {
+                            Some(p) => Some(p),
+                            None => return Err(HELP_TEXT),
+                        };
+                        i += 1;
+                    }
+                    "--level" => {
+                        dir.file.compression = match value.and_then(|v| v.parse().ok()) {
+                            Some(level @ 1..=22) => Some(level),
+                            _ => return Err(HELP_TEXT),
+                        };
+                        i += 1;
+                    }
+                    "-o" | "--output" => {
+                        dir.output = match value {
+                            Some(o) => Some(o),
+                            None => return Err(HELP_TEXT),
+                        };
+                        i += 1;
+                    }
+                    "--keyfile" => {
+                        keyfile = match value {
+                            Some(k) => Some(k),
+                            None => return Err(HELP_TEXT),
+                        };
+                        i += 1;
+                    }
+                    "--links" => {
+                        dir.links = match value {
+                            Some("skip") => LinkPolicy::Skip,
+                            Some("follow") => LinkPolicy::Follow,
+                            Some("fail") => LinkPolicy::Fail,
+                            _ => return Err(HELP_TEXT),
+                        };
+                        i += 1;
+                    }
+                    "--special" => {
+                        dir.special = match value {
+                            Some("skip") => SpecialPolicy::Skip,
+                            Some("fail") => SpecialPolicy::Fail,
+                            _ => return Err(HELP_TEXT),
+                        };
+                        i += 1;
+                    }
+                    "-p" | "-s" | "--saved" | "-m" | "--master"
+                    | "--password-file" | "--password-env" | "--password-fd" => {
+                        pass = match get_pass(&args[i], args.get(i + 1)) {
+                            Some(p) => Some(p),
+                            None => return Err(HELP_TEXT),
+                        };
+                        if pass != Some(Pass::Master) {
+                            i += 1;
+                        }
+                    }
+                    // A lone `-` is a filename standing for stdin/stdout
+                    arg if file.is_none() && (arg == "-" || !arg.starts_with('-')) => {
+                        file = Some(arg)
+                    }
+                    _ => return Err(HELP_TEXT),
+                }
+                i += 1;
+            }
+            if op == Operation::Ls && file.is_none() {
+                file = Some(".");
+            }
+            // Which saved password to use is found out when there are some
+            let try_saved = op == Operation::Decrypt && !recursive && file != Some("-") && keystore::exists();
+            let pass = decrypt_pass(pass, keyfile, defaults, try_saved);
+
+            // The defaults only fill in what the options leave out
+            let encrypting = op == Operation::Encrypt;
+            let replace = replace.unwrap_or(
+                (encrypting || op == Operation::Decrypt) && defaults.replace == Some(true));
+            if recursive && dir.output.is_none() {
+                dir.output = defaults.output.as_deref();
+            }
+            if encrypting && !no_compress && dir.file.compression.is_none() {
+                dir.file.compression = defaults.compress;
+            }
+            if encrypting && !no_pad && dir.file.padding.is_none() {
+                dir.file.padding = parse_padding(defaults.pad.as_deref());
+            }
+
+            let auto = matches!(pass, Some(Pass::Auto { prompt: _ }));
+            let obfuscate = dir.file.obfuscate;
+            if file.is_none() || (!recursive && dir.output.is_some())
+                || (obfuscate && (op != Operation::Encrypt || file == Some("-") || !dir.file.metadata))
+                || ((dir.file.compression.is_some() || dir.file.padding.is_some() || dir.file.fingerprint)
+                    && op != Operation::Encrypt)
+                || (auto && (op == Operation::Encrypt || op == Operation::Ls || recursive || file == Some("-")))
+            {
                 return Err(HELP_TEXT);
             }
-            let file;
-            let pass;
-            match args[x + 2].as_str() {
-                "-p" | "-s" | "--saved" | "-m" | "--master" => {
-                    pass = match get_pass(&args[x + 2], args.get(x + 3)) {
-                        Some(p) => p,
-                        _ => return Err(HELP_TEXT),
-                    };
-                    file = match args.get(x + 4) {
-                        Some(f) => f.as_str(),
-                        _ => return Err(HELP_TEXT),
+            dir.replace = replace;
+            dir.file.keyfile = keyfile.is_some();
+
+            return Ok(Config {
+                file,
+                pass,
+                keyfile,
+                replace,
+                force,
+                recursive,
+                dir,
+                ..Config::new(op)
+            });
+        }
+
+        if op == Operation::Info {
+            let file = match args.get(x + 2) {
+                Some(f) => f.as_str(),
+                None => return Err(HELP_TEXT),
+            };
+
+            return Ok(Config {
+                file: Some(file),
+                ..Config::new(op)
+            });
+        }
+
+        if op == Operation::Exec {
+            // Everything after `--` is the command to run
+            let end = match args.iter().skip(x + 2).position(|a| a == "--") {
+                Some(p) => p + x + 2,
+                None => return Err(HELP_TEXT),
+            };
+            if end + 1 >= args.len() {
+                return Err(HELP_TEXT);
+            }
+
+            let mut file = None;
+            let mut pass = None;
+            let mut keyfile = None;
+            let mut i = x + 2;
+            while i < end {
+                let value = args[..end].get(i + 1);
+                match args[i].as_str() {
+                    "-f" | "--file" => {
+                        file = match value {
+                            Some(f) => Some(f.as_str()),
+                            None => return Err(HELP_TEXT),
+                        };
+                        i += 2;
+                    }
+                    "--keyfile" => {
+                        keyfile = match value {
+                            Some(k) => Some(k.as_str()),
+                            None => return Err(HELP_TEXT),
+                        };
+                        i += 2;
+                    }
+                    flag => {
+                        pass = match get_pass(flag, value) {
+                            Some(p) => Some(p),
+                            None => return Err(HELP_TEXT),
+                        };
+                        i += if pass == Some(Pass::Master) { 1 } else { 2 };
+                    }
+                }
+            }
+            if file.is_none() {
+                return Err(HELP_TEXT);
+            }
+
+            return Ok(Config {
+                file,
+                pass: key_pass(pass, keyfile, defaults),
+                keyfile,
+                command: Some(&args[end + 1..]),
+                ..Config::new(op)
+            });
+        }
+
+        if let Operation::Archive(command) = op {
+            let mut file = None;
+            let mut pass = None;
+            let mut paths = Vec::new();
+            let mut keyfile = None;
+            let mut dir = DirOptions::default();
+
+            let mut i = x + 3;
+            while i < args.len() {
+                match args[i].as_str() {
+                    "-o" | "--output" => {
+                        dir.output = match args.get(i + 1) {
+                            Some(o) => Some(o.as_str()),
+                            None => return Err(HELP_TEXT),
+                        };
+                        i += 1;
                     }
+                    "--keyfile" => {
+                        keyfile = match args.get(i + 1) {
+                            Some(k) => Some(k.as_str()),
+                            None => return Err(HELP_TEXT),
+                        };
+                        i += 1;
+                    }
+                    "-p" | "-s" | "--saved" | "-m" | "--master"
+                    | "--password-file" | "--password-env" | "--password-fd" => {
+                        pass = match get_pass(&args[i], args.get(i + 1)) {
+                            Some(p) => Some(p),
+                            None => return Err(HELP_TEXT),
+                        };
+                        if pass != Some(Pass::Master) {
+                            i += 1;
+                        }
+                    }
+                    arg if arg.starts_with('-') => return Err(HELP_TEXT),
+                    arg if file.is_none() => file = Some(arg),
+                    arg => paths.push(arg),
                 }
-                filename => {
-                    file = filename;
-                    if args.len() < x + 4 {
-                        return Err(HELP_TEXT);
+                i += 1;
+            }
+
+            let needs_paths = command == ArchiveCommand::Create || command == ArchiveCommand::Add;
+            if file.is_none() || (needs_paths && paths.is_empty())
+                || (dir.output.is_some() && command != ArchiveCommand::Extract)
+                || (!paths.is_empty() && command == ArchiveCommand::List)
+            {
+                return Err(HELP_TEXT);
+            }
+
+            if command == ArchiveCommand::Extract && dir.output.is_none() {
+                dir.output = defaults.output.as_deref();
+            }
+            dir.file.keyfile = keyfile.is_some();
+
+            return Ok(Config {
+                file,
+                pass: key_pass(pass, keyfile, defaults),
+                keyfile,
+                dir,
+                paths,
+                ..Config::new(op)
+            });
+        }
+
+        if op == Operation::Keys(KeysCommand::Export) || op == Operation::Keys(KeysCommand::Import) {
+            let mut file = None;
+            let mut pass = None;
+            let mut source = None;
+            let mut paths = Vec::new();
+            let mut on_conflict = Conflict::Fail;
+            let mut allow_weak = false;
+
+            let mut i = x + 3;
+            while i < args.len() {
+                let value = args.get(i + 1).map(|v| v.as_str());
+                match args[i].as_str() {
+                    "-m" | "--master" => pass = Some(Pass::Master),
+                    "--allow-weak" if op == Operation::Keys(KeysCommand::Export) => allow_weak = true,
+                    "--on-conflict" if op == Operation::Keys(KeysCommand::Import) => {
+                        on_conflict = match value {
+                            Some("skip") => Conflict::Skip,
+                            Some("overwrite") => Conflict::Overwrite,
+                            Some("rename") => Conflict::Rename,
+                            _ => return Err(HELP_TEXT),
+                        };
+                        i += 1;
                     }
-                    pass = match get_pass(&args[x + 3], args.get(x + 4)) {
-                        Some(p) => p,
-                        _ => return Err(HELP_TEXT),
+                    flag @ ("--password-file" | "--password-env" | "--password-fd") => {
+                        source = match value.and_then(|v| PassSource::parse(flag, v)) {
+                            Some(s) => Some(s),
+                            None => return Err(HELP_TEXT),
+                        };
+                        i += 1;
                     }
+                    arg if arg.starts_with('-') => return Err(HELP_TEXT),
+                    arg if file.is_none() => file = Some(arg),
+                    arg if op == Operation::Keys(KeysCommand::Export) => paths.push(arg),
+                    _ => return Err(HELP_TEXT),
                 }
+                i += 1;
+            }
+            if file.is_none() {
+                return Err(HELP_TEXT);
+            }
+
+            let saved = Keystore::open()?;
+            if op == Operation::Keys(KeysCommand::Import) && saved.master().is_none() {
+                return Err("No master password set, the saved passwords are encrypted with it.\n\
+                            Set one using `cryptile set -m` command");
+            }
+
+            return Ok(Config {
+                file,
+                pass,
+                source,
+                saved: Some(saved),
+                paths,
+                on_conflict,
+                allow_weak,
+                ..Config::new(op)
+            });
+        }
+
+        if let Operation::Keys(command) = op {
+            let paths: Vec<&str> = args[x + 3..].iter().map(|a| a.as_str()).collect();
+            let pass = match (command, paths.as_slice()) {
+                (KeysCommand::List, []) => None,
+                (KeysCommand::Remove, [_]) | (KeysCommand::Rename, [_, _]) => None,
+                (KeysCommand::Fingerprint, ["-m" | "--master"]) => Some(Pass::Master),
+                (KeysCommand::Fingerprint, [id]) => Some(Pass::Saved { identifier: Some(id) }),
+                (KeysCommand::Backup, [id]) => Some(Pass::Saved { identifier: Some(id) }),
+                (KeysCommand::Restore, [_]) => None,
+                _ => return Err(HELP_TEXT),
+            };
+            if paths.iter().any(|p| p.starts_with('-')) && pass != Some(Pass::Master) {
+                return Err(HELP_TEXT);
+            }
+
+            let saved = Keystore::open()?;
+            if command == KeysCommand::Restore {
+                if saved.master().is_none() {
+                    return Err("No master password set, the saved passwords are encrypted with it.\n\
+                                Set one using `cryptile set -m` command");
+                }
+                if saved.search(paths[0]).is_some() {
+                    return Err("A saved password with the given identifier already exists");
+                }
+            }
+
+            return Ok(Config {
+                pass,
+                saved: Some(saved),
+                paths,
+                ..Config::new(op)
+            });
+        }
+
+        if let Operation::Config(command) = op {
+            let paths: Vec<&str> = args[x + 3..].iter().map(|a| a.as_str()).collect();
+            let count = match command {
+                ConfigCommand::Get => 0..=1,
+                ConfigCommand::Set => 2..=2,
+                ConfigCommand::Unset => 1..=1,
+            };
+            if !count.contains(&paths.len()) {
+                return Err(HELP_TEXT);
+            }
+
+            return Ok(Config {
+                paths,
+                ..Config::new(op)
+            });
+        }
+
+        if op == Operation::KeyfileGenerate {
+            let file = match (args.get(x + 3), args.len()) {
+                (Some(f), n) if n == x + 4 => f.as_str(),
+                _ => return Err(HELP_TEXT),
             };
-            let replace = args.contains(&"--replace".to_owned());
 
             return Ok(Config {
-                operation: op,
                 file: Some(file),
-                pass: Some(pass),
-                saved: None,
-                replace,
+                ..Config::new(op)
+            });
+        }
+
+        if let Operation::Generate(mut generate) = op {
+            let mut i = match args.get(x + 2).map(|a| a.as_str()) {
+                Some("password" | "passphrase") => x + 3,
+                _ => x + 2,
+            };
+            let mut save = None;
+            let mut allow_weak = false;
+            while i < args.len() {
+                let value = args.get(i + 1).map(|v| v.as_str());
+                match (&mut generate, args[i].as_str(), value) {
+                    (_, "--save", Some(id)) => save = Some(id),
+                    (_, "--allow-weak", _) => {
+                        allow_weak = true;
+                        i += 1;
+                        continue;
+                    }
+                    (Generate::Password { length, charset: _ }, "--length", Some(n)) => {
+                        *length = match n.parse() {
+                            Ok(n) if (1..=1024).contains(&n) => n,
+                            _ => return Err("Error: The length must be a number from 1 to 1024"),
+                        }
+                    }
+                    (Generate::Password { length: _, charset }, "--charset", Some(set)) => {
+                        *charset = match set {
+                            "alnum" => Charset::Alphanumeric,
+                            "symbols" => Charset::Symbols,
+                            "digits" => Charset::Digits,
+                            "hex" => Charset::Hex,
+                            _ => return Err(HELP_TEXT),
+                        }
+                    }
+                    (Generate::Passphrase { words, separator: _ }, "--words", Some(n)) => {
+                        *words = match n.parse() {
+                            Ok(n) if (1..=128).contains(&n) => n,
+                            _ => return Err("Error: The number of words must be from 1 to 128"),
+                        }
+                    }
+                    (Generate::Passphrase { words: _, separator }, "--separator", Some(sep)) => *separator = sep,
+                    _ => return Err(HELP_TEXT),
+                }
+                i += 2;
+            }
+
+            let (pass, saved) = match save {
+                Some(id) => {
+                    let saved = Keystore::open()?;
+                    if saved.master().is_none() {
+                        return Err("No master password set, the saved passwords are encrypted with it.\n\
+                                    Set one using `cryptile set -m` command");
+                    }
+                    (Some(Pass::Saved { identifier: Some(id) }), Some(saved))
+                }
+                None => (None, None),
+            };
+
+            return Ok(Config {
+                pass,
+                saved,
+                allow_weak,
+                ..Config::new(Operation::Generate(generate))
             });
         }
 
         if op == Operation::Set {
-            if let None = args.get(x + 2) {
+            if args.get(x + 2).is_none() {
                 return Err(HELP_TEXT);
             }
 
-            let saved = match get_saved_pass() {
-                Ok(s) => s,
-                Err(m) => return Err(m),
+            let pass = match args[x + 2].as_str() {
+                "-p" => Pass::Saved { identifier: None },
+                "-m" | "--master" => Pass::Master,
+                _ => return Err(HELP_TEXT),
             };
 
-            let pass;
-            match args[x + 2].as_str() {
-                "-p" => pass = Pass::Saved { identifier: None },
-                "-m" | "--master" => pass = Pass::Master,
+            let rest: Vec<&String> = args[x + 3..].iter().filter(|a| *a != "--allow-weak").collect();
+            let allow_weak = rest.len() < args.len() - (x + 3);
+            let source = match rest.as_slice() {
+                [] => None,
+                [flag, value] => {
+                    match PassSource::parse(flag, value) {
+                        Some(s) => Some(s),
+                        None => return Err(HELP_TEXT),
+                    }
+                }
                 _ => return Err(HELP_TEXT),
+            };
+
+            let saved = Keystore::open()?;
+            if pass != Pass::Master && saved.master().is_none() {
+                return Err("No master password set, the saved passwords are encrypted with it.\n\
+                            Set one using `cryptile set -m` command");
             }
 
             return Ok(Config {
-                operation: op,
-                file: None,
                 pass: Some(pass),
+                source,
                 saved: Some(saved),
-                replace: false,
+                allow_weak,
+                ..Config::new(op)
             });
         }
 
-        return Err(HELP_TEXT);
+        Err(HELP_TEXT)
     }
 
     pub fn get_key(&self) -> Result<[u8; 32], &str> {
         match self.pass.as_ref().unwrap() {
-            Pass::Given { given } => {
-                let pass: Vec<u8> = (*given).bytes().collect();
-                return Ok(Hash::hash(&pass));
-            }
+            Pass::Given { given } => Ok(key_of(given)),
+            Pass::Prompt | Pass::Auto { prompt: _ } => Err("No password given"),
+            Pass::Read { source } => source.read().map(|p| key_of(&p)),
             Pass::Saved { identifier } => {
-                let saved = match get_saved_pass() {
-                    Ok(s) => s,
-                    Err(m) => return Err(m),
-                };
-
-                match saved.keys {
-                    Some(_) => {
-                        match saved.search(identifier.unwrap()) {
-                            Some(k) => Ok(k),
-                            None => Err("No saved password with the given identifier found")
-                        }
-                    }
-                    None => Err("No Passwords saved.\n\
-                                Save one using `cryptile set -p` command")  
+                let saved = Keystore::open()?;
+
+                if saved.keys().is_empty() {
+                    return Err("No Passwords saved.\n\
+                                Save one using `cryptile set -p` command");
+                }
+                match saved.search(identifier.unwrap()) {
+                    Some(k) => Ok(k),
+                    None => Err("No saved password with the given identifier found")
                 }
             }
             Pass::Master => {
-                let saved = match get_saved_pass() {
-                    Ok(s) => s,
-                    Err(m) => return Err(m),
-                };
+                let saved = Keystore::open()?;
 
-                match saved.master {
+                match saved.master() {
                     Some(m) => Ok(m),
                     None => Err("No master password set.\n\
                                 Set one using `cryptile set -m` command"),
@@ -256,31 +860,48 @@ impl<'a> Config<'a> {
         }
     }
 
-    pub fn set_pass(&mut self, pass: String, id: Option<String>) {
-        if self.operation == Operation::Set {
-            let pass: Vec<u8> = pass.bytes().collect();
-            let pass = Hash::hash(&pass);
+    pub fn set_pass(&mut self, pass: String, id: Option<String>) -> Result<(), &str> {
+        if matches!(self.operation, Operation::Set | Operation::Generate(_)) {
+            let saved = self.saved.as_mut().unwrap();
 
             if let Some(Pass::Master) = self.pass {
-                let mut saved = self.saved.take().unwrap();
-                saved.master = Some(pass);
-                self.saved = Some(saved);
+                saved.set_master(&pass)?;
             } else if let Some(Pass::Saved { identifier: _ }) = self.pass {
-                let mut saved = self.saved.take().unwrap();
-
-                let mut keys = match saved.keys {
-                    Some(keys) => keys,
-                    None => Vec::new(),
-                };
-
-                keys.push(Key {
-                    key: pass,
+                saved.add(Key {
+                    key: key_of(&pass),
                     identifier: id.unwrap(),
-                });
-                saved.keys = Some(keys);
-                self.saved = Some(saved);
+                })?;
             }
         }
+        Ok(())
+    }
+
+    pub fn on_conflict(&self) -> Conflict {
+        self.on_conflict
+    }
+
+    pub fn allow_weak(&self) -> bool {
+        self.allow_weak
+    }
+
+    pub fn keystore(&mut self) -> &mut Keystore {
+        self.saved.as_mut().unwrap()
+    }
+
+    /// Writes the keystore back if it was changed
+    pub fn save_keystore(&mut self) -> Result<(), &'static str> {
+        match self.saved.as_mut() {
+            Some(saved) if saved.changed() => saved.save(),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn source(&self) -> Option<PassSource<'a>> {
+        self.source
+    }
+
+    pub fn keyfile(&self) -> Option<&'a str> {
+        self.keyfile
     }
 
     pub fn file(&self) -> Option<&str> {
@@ -290,19 +911,29 @@ impl<'a> Config<'a> {
     pub fn replace(&self) -> bool {
         self.replace
     }
-}
 
-impl<'a> Drop for Config<'a> {
-    fn drop(&mut self) {
-        if let Some(saved) = &self.saved {
-            let config_file = toml::to_string(saved).unwrap();
+    pub fn force(&self) -> bool {
+        self.force
+    }
+
+    pub fn command(&self) -> Option<&[String]> {
+        self.command
+    }
 
-            let path = config_path().unwrap();
-            File::create(path)
-                .unwrap()
-                .write(config_file.as_bytes())
-                .unwrap();
-        }
+    pub fn recursive(&self) -> bool {
+        self.recursive
+    }
+
+    pub fn dir_options(&self) -> &DirOptions<'a> {
+        &self.dir
+    }
+
+    pub fn file_options(&self) -> &FileOptions {
+        &self.dir.file
+    }
+
+    pub fn paths(&self) -> &[&'a str] {
+        &self.paths
     }
 }
 
@@ -314,15 +945,135 @@ const HELP_TEXT: &str = "\
         Commands:\n\
         \tencrypt <FILENAME> [PASSWORD_OPTIONS]       Encrypt file using given password\n\
         \tdecrypt <FILENAME> [PASSWORD_OPTIONS]       Decrypt file using given password\n\
-        \t    Password Options:\n\
-        \t        -p <PASSWORD>                       Specify a password\n\
+        \t    Password Options (prompted for when none is given):\n\
+        \t        -p <PASSWORD>                       Specify a password (visible in the shell history and process list)\n\
         \t        -s, --saved <SAVED_IDENTIFIER>      Use a saved password using it's identifier\n\
         \t        -m, --master                        Use the master password (if set)\n\
-        \tset [SET_OPTIONS]\n\
+        \t        --password-file <PATH>              Read the password from the first line of a file\n\
+        \t        --password-env <VAR>                Read the password from an environment variable\n\
+        \t        --password-fd <FD>                  Read the password from the first line of a file descriptor\n\
+        \t        --keyfile <PATH>                    Use a keyfile, alone or along with a password\n\
+        \t        --auto                              Try the master and every saved password (the default\n\
+        \t                                            of decrypt when passwords are saved)\n\
+        \tverify <PATH> [PASSWORD_OPTIONS]            Check the integrity of an encrypted file, or all in a directory\n\
+        \tcat <FILENAME> [PASSWORD_OPTIONS]           Print the decrypted content without creating any file\n\
+        \tedit <FILENAME> [PASSWORD_OPTIONS]          Edit an encrypted file in $EDITOR and encrypt it again\n\
+        \texec -f <FILENAME> [PASSWORD_OPTIONS] -- <COMMAND>\n\
+        \t                                            Run a command with the variables of an encrypted .env file\n\
+        \tarchive <ARCHIVE_COMMAND> <ARCHIVE> [PASSWORD_OPTIONS]\n\
+        \t    Archive Commands:\n\
+        \t        create <ARCHIVE> <PATHS>...         Pack files and directories into a single encrypted archive\n\
+        \t        list <ARCHIVE>                      List the members of an archive\n\
+        \t        extract <ARCHIVE> [MEMBERS]...      Extract all or the given members (-o <DIRECTORY> to choose where)\n\
+        \t        add <ARCHIVE> <PATHS>...            Add files and directories to an archive\n\
+        \tkeys <KEYS_COMMAND>\n\
+        \t    Keys Commands:\n\
+        \t        list                                List the identifiers of the saved passwords\n\
+        \t        remove <ID>                         Remove a saved password\n\
+        \t        rename <ID> <NEW_ID>                Change the identifier of a saved password\n\
+        \t        fingerprint <ID|-m>                 Show the fingerprint of a saved password (or the master)\n\
+        \t        export <BUNDLE> [IDS]... [-m]       Write the given (or all) saved passwords, and the master with -m,\n\
+        \t                                            to a bundle encrypted with a password of its own\n\
+        \t        import <BUNDLE> [-m]                Add the passwords of a bundle, and its master with -m\n\
+        \t            --on-conflict <skip|overwrite|rename>\n\
+        \t                                            What to do with identifiers taken by other passwords\n\
+        \t        backup <ID>                         Print a saved password as 24 words, to keep on paper\n\
+        \t        restore <ID>                        Save a password again from the 24 words of its backup\n\
+        \t    The bundle password is prompted for, unless read with --password-file, --password-env or --password-fd\n\
+        \tkeyfile generate <PATH>                     Write a new random keyfile\n\
+        \tgenerate [password|passphrase] [GENERATE_OPTIONS]\n\
+        \t    Print a random password (the default) or a passphrase of words of the BIP39 wordlist\n\
+        \t    Generate Options:\n\
+        \t        --length <1-1024>                   Length of the password (default: 20)\n\
+        \t        --charset <alnum|symbols|digits|hex>\n\
+        \t                                            Characters of the password (default: alnum)\n\
+        \t        --words <1-128>                     Number of words of the passphrase (default: 6)\n\
+        \t        --separator <SEP>                   Text between the words (default: -)\n\
+        \t        --save <ID>                         Also save it as a password with the given identifier\n\
+        \tconfig <CONFIG_COMMAND>\n\
+        \t    Config Commands:\n\
+        \t        get [OPTION]                        Show the default options, or the given one\n\
+        \t        set <OPTION> <VALUE>                Set a default option, used when the flags don't give it\n\
+        \t        unset <OPTION>                      Remove a default option\n\
+        \t    Options:\n\
+        \t        password <prompt|master|saved:ID>   Password option of commands given none\n\
+        \t        output <DIRECTORY>                  Output directory of -r and of extracted archives\n\
+        \t        replace <true|false>                Whether to remove the original file (--no-replace to keep it)\n\
+        \t        compress <1-22>                     Compression level when encrypting (--no-compress to not compress)\n\
+        \t        pad <pow2|padme>                    Padding when encrypting (--no-pad to not pad)\n\
+        \t    The cipher, key derivation and thread count can't be chosen yet\n\
+        \tinfo <FILENAME>                             Show the header information of an encrypted file\n\
+        \tls [DIRECTORY] [PASSWORD_OPTIONS]           List the original names of the encrypted files in a directory\n\
+        \tset [SET_OPTIONS] [PASSWORD_SOURCE]\n\
         \t    Set Options:\n\
         \t        -m, --master                        Set a master password\n\
         \t        -p                                  Save a password and it's identifier name\n\
+        \t    The password is prompted for, unless read with --password-file, --password-env or --password-fd\n\
         Flags:\n\
         \t-h, --help                                  Display this help information\n\
         \t--replace                                   Remove the original file after Encryption/Decryption\n\
+        \t--allow-weak                                With set, generate --save and keys export, accept a password\n\
+        \t                                            estimated to be too easy to crack\n\
+        \t--force                                     Let cat print binary content to a terminal\n\
+        \t-r, --recursive                             Encrypt/Decrypt every file inside the given directory\n\
+        \t-o, --output <DIRECTORY>                    With -r, write the files to a mirror of the directory\n\
+        \t--links <skip|follow|fail>                  With -r, what to do with symbolic links (default: skip)\n\
+        \t--special <skip|fail>                       With -r, what to do with special files (default: skip)\n\
+        \t--no-metadata                               Don't store (or restore) the name, permissions and times of the file\n\
+        \t--xattrs                                    Also store (or restore) extended attributes\n\
+        \t--obfuscate                                 Give the encrypted file a random name, hiding the original one\n\
+        \t--compress                                  Compress the file with zstd before encrypting it\n\
+        \t--level <1-22>                              Compress at the given level (default: 3)\n\
+        \t--fingerprint                               Record the fingerprint of the key, to find it among saved ones at once\n\
+        \t--pad <pow2|padme>                          Pad the content so that the encrypted size doesn't reveal its size\n\
+        \t--config <PATH>                             Use the keystore at PATH (or $CRYPTILE_CONFIG)\n\
+        \t--profile <NAME>                            Use the keystore of a named profile (or $CRYPTILE_PROFILE)\n\
+        \n\
+        Use - as the FILENAME of encrypt/decrypt to read from stdin and write to stdout.\n\
           ";
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn password_fd_shared_with_data() {
+        use std::io::Read;
+        use std::os::fd::AsRawFd;
+
+        let path = env::temp_dir().join(format!("cryptile-password-fd-{}", std::process::id()));
+        fs::write(&path, b"secret\r\ndata\nmore data").unwrap();
+        let mut file = File::open(&path).unwrap();
+        let source = PassSource::Fd(file.as_raw_fd());
+        assert_eq!(source.read().unwrap(), "secret");
+
+        // The descriptor is still open, right after the password
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"data\nmore data");
+        assert_eq!(source.read(), Err("The password read is empty"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pass_without_option() {
+        let none = Defaults::default();
+        assert!(key_pass(None, None, &none) == Some(Pass::Prompt));
+        assert!(key_pass(None, Some("usb.key"), &none).is_none());
+        assert!(key_pass(Some(Pass::Master), Some("usb.key"), &none) == Some(Pass::Master));
+
+        let master = Defaults { password: Some("master".to_owned()), ..Defaults::default() };
+        assert!(key_pass(None, None, &master) == Some(Pass::Master));
+        assert!(key_pass(Some(Pass::Given { given: "pw" }), None, &master) == Some(Pass::Given { given: "pw" }));
+        let saved = Defaults { password: Some("saved:work".to_owned()), ..Defaults::default() };
+        assert!(key_pass(None, None, &saved) == Some(Pass::Saved { identifier: Some("work") }));
+
+        assert!(decrypt_pass(None, None, &none, true) == Some(Pass::Auto { prompt: true }));
+        assert!(decrypt_pass(None, None, &none, false) == Some(Pass::Prompt));
+        assert!(decrypt_pass(None, None, &master, true) == Some(Pass::Master));
+        assert!(decrypt_pass(None, Some("usb.key"), &none, true).is_none());
+        assert!(decrypt_pass(Some(Pass::Auto { prompt: false }), None, &none, true) == Some(Pass::Auto { prompt: false }));
+    }
+}
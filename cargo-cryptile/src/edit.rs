@@ -0,0 +1,284 @@
+use cargo_cryptile as cryptile;
+use hmac_sha256::Hash;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Private directory holding the decrypted copy of a file while it is edited.
+/// Everything inside it is overwritten with zeros and removed when dropped,
+/// so the plaintext doesn't outlive the edit even if something fails
+struct PrivateDir {
+    path: PathBuf,
+}
+
+impl PrivateDir {
+    fn create() -> Result<PrivateDir, Error> {
+        let base = temp_base();
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+
+        for attempt in 0..100u32 {
+            let mut path = base.clone();
+            path.push(format!("cryptile-edit-{}-{}-{}", std::process::id(), nanos, attempt));
+
+            match create_private_dir(&path) {
+                Ok(()) => return Ok(PrivateDir { path }),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(Error::from(ErrorKind::AlreadyExists))
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        // Editors may leave swap or backup files next to the edited file
+        if let Ok(entries) = fs::read_dir(&self.path) {
+            for entry in entries.flatten() {
+                wipe(&entry.path());
+            }
+        }
+        _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Prefers a memory backed filesystem so the plaintext never reaches the disk
+fn temp_base() -> PathBuf {
+    let shm = Path::new("/dev/shm");
+    if cfg!(target_os = "linux") && shm.is_dir() {
+        return shm.to_owned();
+    }
+    env::temp_dir()
+}
+
+#[cfg(unix)]
+fn create_private_dir(path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::DirBuilderExt;
+    fs::DirBuilder::new().mode(0o700).create(path)
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(path: &Path) -> Result<(), Error> {
+    fs::create_dir(path)
+}
+
+#[cfg(unix)]
+fn create_private_file(path: &Path) -> Result<File, Error> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private_file(path: &Path) -> Result<File, Error> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+/// Overwrites a file with zeros before removing it
+fn wipe(path: &Path) {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.is_file() {
+            if let Ok(mut file) = OpenOptions::new().write(true).open(path) {
+                let zeros = vec![0u8; 65_536];
+                let mut remaining = metadata.len();
+                while remaining > 0 {
+                    let n = remaining.min(zeros.len() as u64) as usize;
+                    if file.write_all(&zeros[..n]).is_err() {
+                        break;
+                    }
+                    remaining -= n as u64;
+                }
+                _ = file.sync_all();
+            }
+        }
+    }
+    _ = fs::remove_file(path);
+}
+
+fn editor_command() -> Vec<String> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_default();
+
+    let command: Vec<String> = editor.split_whitespace().map(|s| s.to_owned()).collect();
+    if command.is_empty() {
+        return vec![if cfg!(windows) { "notepad" } else { "vi" }.to_owned()];
+    }
+    command
+}
+
+fn hash_file(path: &Path) -> Result<[u8; 32], Error> {
+    Ok(Hash::hash(&fs::read(path)?))
+}
+
+/// Decrypts `filename` into a private temporary file, opens it in the
+/// user's editor and re-encrypts it with the same key if it was changed.
+/// Returns whether the file was changed
+pub fn edit(filename: &str, key: &[u8; 32]) -> Result<bool, Error> {
+    edit_with(filename, key, &editor_command())
+}
+
+/// Same as `edit`, with the editor run as `command` followed by the path of the file
+fn edit_with(filename: &str, key: &[u8; 32], command: &[String]) -> Result<bool, Error> {
+    // Written again as a plain file, an archive would be lost
+    if cryptile::inspect(filename)?.archive {
+        return Err(Error::other(format!("{} is an archive, use `cryptile archive extract` to decrypt it", filename)));
+    }
+    if !cryptile::is_correct_key(filename, key)? {
+        return Err(Error::from(ErrorKind::InvalidInput));
+    }
+    let stored = cryptile::read_metadata(filename, key)?;
+
+    let dir = PrivateDir::create()?;
+    let name = stored.as_ref()
+        .and_then(|m| m.safe_name())
+        .or_else(|| Path::new(filename)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(cryptile::FILE_EXTENSION)))
+        .filter(|n| !n.is_empty())
+        .unwrap_or("plaintext");
+    let mut plaintext = dir.path.clone();
+    plaintext.push(name);
+    let plaintext_name = plaintext.to_str().unwrap().to_owned();
+
+    let mut writer = create_private_file(&plaintext)?;
+    cryptile::decrypt_to(filename, key, &mut writer)?;
+    writer.sync_all()?;
+    drop(writer);
+
+    let before = hash_file(&plaintext)?;
+
+    let status = Command::new(&command[0])
+        .args(&command[1..])
+        .arg(&plaintext)
+        .status()?;
+    let changed = hash_file(&plaintext)? != before;
+    if !status.success() {
+        // What a failing editor left may well be half written
+        let outcome = if changed { "the changes were discarded" } else { "no changes were made" };
+        return Err(Error::other(format!("Editor `{}` exited with {}, {} and {} was left untouched",
+            command.join(" "), status, outcome, filename)));
+    }
+    if !changed {
+        return Ok(false);
+    }
+
+    // Written next to the original and renamed over it,
+    // so the original stays intact if encryption fails
+    let new_encrypted = filename.to_owned() + ".edit";
+    let permissions = fs::metadata(filename)?.permissions();
+    let info = cryptile::inspect(filename)?;
+    let options = cryptile::FileOptions {
+        compression: info.compression_level,
+        padding: info.padding,
+        keyfile: info.keyfile,
+        fingerprint: info.fingerprint.is_some(),
+        ..cryptile::FileOptions::default()
+    };
+    // The stored metadata is kept, apart from the times of the edit
+    let metadata = match stored {
+        Some(stored) => {
+            let edited = cryptile::Metadata::read(&plaintext_name, false)?;
+            Some(cryptile::Metadata { modified: edited.modified, accessed: edited.accessed, ..stored })
+        }
+        None => None,
+    };
+    if let Err(e) = cryptile::encrypt_to_path_with_metadata(&plaintext_name, &new_encrypted, key,
+        metadata.as_ref(), &options)
+        .and_then(|_| fs::set_permissions(&new_encrypted, permissions))
+    {
+        cryptile::delete(&new_encrypted);
+        return Err(e);
+    }
+    if let Err(e) = fs::rename(&new_encrypted, filename) {
+        cryptile::delete(&new_encrypted);
+        return Err(e);
+    }
+
+    Ok(true)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    /// An encrypted file holding `content`, compressed and with a fingerprint
+    fn encrypted_file(name: &str, content: &[u8]) -> String {
+        let filename = env::temp_dir().join(format!("cryptile-edit-test-{}-{}", std::process::id(), name));
+        let filename = filename.to_str().unwrap().to_owned();
+        fs::write(&filename, content).unwrap();
+        let options = cryptile::FileOptions {
+            compression: Some(cryptile::DEFAULT_COMPRESSION_LEVEL),
+            fingerprint: true,
+            ..cryptile::FileOptions::default()
+        };
+        let encrypted = cryptile::encrypt_with(&filename, &KEY, &options).unwrap();
+        cryptile::delete(&filename);
+        encrypted
+    }
+
+    fn script(script: &str) -> Vec<String> {
+        ["sh", "-c", script].iter().map(|s| s.to_string()).collect()
+    }
+
+    fn decrypted(filename: &str) -> Vec<u8> {
+        let mut content = Vec::new();
+        cryptile::decrypt_to(filename, &KEY, &mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn unchanged_file_not_rewritten() {
+        let encrypted = encrypted_file("unchanged.txt", b"notes");
+        let before = fs::read(&encrypted).unwrap();
+        assert!(!edit_with(&encrypted, &KEY, &script("true")).unwrap());
+        assert_eq!(fs::read(&encrypted).unwrap(), before);
+        cryptile::delete(&encrypted);
+    }
+
+    #[test]
+    fn edited_file_encrypted_again() {
+        let encrypted = encrypted_file("edited.txt", b"notes\n");
+        let info = cryptile::inspect(&encrypted).unwrap();
+        assert!(edit_with(&encrypted, &KEY, &script("echo more >> \"$0\"")).unwrap());
+
+        assert_eq!(decrypted(&encrypted), b"notes\nmore\n");
+        let edited = cryptile::inspect(&encrypted).unwrap();
+        assert_eq!(edited.compression_level, info.compression_level);
+        assert_eq!(edited.fingerprint, info.fingerprint);
+        assert!(cryptile::read_metadata(&encrypted, &KEY).unwrap().unwrap().name.ends_with("edited.txt"));
+        assert!(!Path::new(&(encrypted.clone() + ".edit")).exists());
+        cryptile::delete(&encrypted);
+    }
+
+    #[test]
+    fn failing_editor_leaves_file() {
+        let encrypted = encrypted_file("failing.txt", b"notes");
+        let before = fs::read(&encrypted).unwrap();
+        let err = edit_with(&encrypted, &KEY, &script("echo more >> \"$0\"; exit 3")).unwrap_err();
+        assert!(err.to_string().contains("the changes were discarded"));
+        let err = edit_with(&encrypted, &KEY, &script("exit 3")).unwrap_err();
+        assert!(err.to_string().contains("no changes were made"));
+        assert_eq!(fs::read(&encrypted).unwrap(), before);
+
+        let archive = encrypted.clone() + ".archive.cryptile";
+        cryptile::archive::create(&[&encrypted], &archive, &KEY).unwrap();
+        let before = fs::read(&archive).unwrap();
+        let err = edit_with(&archive, &KEY, &script("echo more >> \"$0\"")).unwrap_err();
+        assert!(err.to_string().contains("is an archive"));
+        assert_eq!(fs::read(&archive).unwrap(), before);
+        cryptile::delete(&archive);
+
+        let wrong_key = [8; 32];
+        assert_eq!(edit_with(&encrypted, &wrong_key, &script("true")).unwrap_err().kind(), ErrorKind::InvalidInput);
+        cryptile::delete(&encrypted);
+    }
+}
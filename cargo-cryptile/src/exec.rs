@@ -0,0 +1,150 @@
+use cargo_cryptile as cryptile;
+use std::io::{Error, ErrorKind};
+use std::process::Command;
+
+/// Parses the contents of a dotenv file.
+///
+/// Supports `KEY=VALUE` lines with an optional `export ` prefix,
+/// comments, single quoted (literal) and double quoted values
+/// (with `\n`, `\t`, `\"` and `\\` escapes, possibly spanning lines)
+fn parse_dotenv(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut vars = Vec::new();
+    let mut lines = contents.lines().enumerate();
+
+    while let Some((n, line)) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim_start()),
+            None => return Err(format!("line {}: expected KEY=VALUE", n + 1)),
+        };
+        if name.is_empty()
+            || name.starts_with(|c: char| c.is_ascii_digit())
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(format!("line {}: invalid variable name `{}`", n + 1, name));
+        }
+
+        let value = if let Some(rest) = value.strip_prefix('\'') {
+            match rest.find('\'') {
+                Some(end) => rest[..end].to_owned(),
+                None => return Err(format!("line {}: unterminated quote", n + 1)),
+            }
+        } else if let Some(rest) = value.strip_prefix('"') {
+            let mut value = String::new();
+            let mut rest = rest.to_owned();
+            loop {
+                let mut chars = rest.chars();
+                let mut closed = false;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => {
+                            closed = true;
+                            break;
+                        }
+                        '\\' => match chars.next() {
+                            Some('n') => value.push('\n'),
+                            Some('t') => value.push('\t'),
+                            Some('r') => value.push('\r'),
+                            Some(c) => value.push(c),
+                            None => value.push('\\'),
+                        },
+                        c => value.push(c),
+                    }
+                }
+                if closed {
+                    break;
+                }
+                match lines.next() {
+                    Some((_, next)) => {
+                        value.push('\n');
+                        rest = next.to_owned();
+                    }
+                    None => return Err(format!("line {}: unterminated quote", n + 1)),
+                }
+            }
+            value
+        } else {
+            // Unquoted values end at an inline comment
+            let end = value.find(" #").unwrap_or(value.len());
+            value[..end].trim_end().to_owned()
+        };
+
+        vars.push((name.to_owned(), value));
+    }
+
+    Ok(vars)
+}
+
+/// Decrypts a dotenv file in memory and runs `command` with
+/// the variables it defines added to its environment.
+/// Returns the exit code of the command
+pub fn exec(filename: &str, key: &[u8; 32], command: &[String]) -> Result<i32, Error> {
+    let mut contents = Vec::new();
+    let result = cryptile::decrypt_to(filename, key, &mut contents);
+    let vars = result.and_then(|_| {
+        let text = std::str::from_utf8(&contents)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "the environment file isn't valid UTF-8"))?;
+        parse_dotenv(text).map_err(|m| Error::new(ErrorKind::InvalidData, m))
+    });
+    contents.iter_mut().for_each(|b| *b = 0);
+    let vars = vars?;
+
+    let status = Command::new(&command[0])
+        .args(&command[1..])
+        .envs(vars)
+        .status()?;
+
+    Ok(exit_code(status))
+}
+
+#[cfg(unix)]
+fn exit_code(status: std::process::ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    match status.code() {
+        Some(code) => code,
+        // Killed by a signal, reported the way shells do
+        None => 128 + status.signal().unwrap_or(0),
+    }
+}
+
+#[cfg(not(unix))]
+fn exit_code(status: std::process::ExitStatus) -> i32 {
+    status.code().unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dotenv_values() {
+        let vars = parse_dotenv("\
+            # database\n\
+            DB_USER=admin\n\
+            export DB_PASS='p@ss #word'\n\
+            \n\
+            URL = https://example.com # production\n\
+            MULTI=\"line one\\nline \\\"two\\\"\"\n\
+            EMPTY=\n").unwrap();
+
+        assert_eq!(vars, vec![
+            ("DB_USER".to_owned(), "admin".to_owned()),
+            ("DB_PASS".to_owned(), "p@ss #word".to_owned()),
+            ("URL".to_owned(), "https://example.com".to_owned()),
+            ("MULTI".to_owned(), "line one\nline \"two\"".to_owned()),
+            ("EMPTY".to_owned(), "".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn dotenv_errors() {
+        assert!(parse_dotenv("NOT A VARIABLE").is_err());
+        assert!(parse_dotenv("1KEY=value").is_err());
+        assert!(parse_dotenv("KEY=\"unterminated").is_err());
+    }
+}
@@ -0,0 +1,109 @@
+//! Random passwords and passphrases:
+//! characters or words are picked uniformly with the randomness of the
+//! operating system, so the strength of the result is exactly known.
+//!
+//! Passphrases are made of words of the BIP39 English wordlist (bundled),
+//! 2048 common words told apart by their first 4 letters, worth 11 bits each.
+
+use std::io::Error;
+use std::sync::OnceLock;
+
+const WORDLIST: &str = include_str!("wordlist.txt");
+
+/// Characters random passwords are made of
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Charset {
+    /// Letters of both cases and digits
+    Alphanumeric,
+    /// Letters, digits and ASCII punctuation
+    Symbols,
+    Digits,
+    /// Lowercase hexadecimal digits
+    Hex,
+}
+
+impl Charset {
+    pub fn chars(self) -> &'static [u8] {
+        const ALPHANUMERIC: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+        match self {
+            Charset::Alphanumeric => ALPHANUMERIC,
+            Charset::Symbols => b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789!\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~",
+            Charset::Digits => &ALPHANUMERIC[52..],
+            Charset::Hex => b"0123456789abcdef",
+        }
+    }
+}
+
+/// The bundled wordlist, in alphabetical order
+pub fn wordlist() -> &'static [&'static str] {
+    static WORDS: OnceLock<Vec<&'static str>> = OnceLock::new();
+    WORDS.get_or_init(|| WORDLIST.lines().collect())
+}
+
+/// Bits of randomness of `count` uniform picks among `choices`
+pub fn entropy_bits(choices: usize, count: usize) -> f64 {
+    count as f64 * (choices as f64).log2()
+}
+
+/// A uniform random number below `n`
+fn random_below(n: usize) -> Result<usize, Error> {
+    let n = n as u64;
+    // Values from `zone` on would make the lowest numbers more likely
+    let zone = u64::MAX - u64::MAX % n;
+    loop {
+        let mut bytes = [0u8; 8];
+        getrandom::fill(&mut bytes)?;
+        let value = u64::from_le_bytes(bytes);
+        if value < zone {
+            return Ok((value % n) as usize);
+        }
+    }
+}
+
+/// Function to generate a random password of `length` characters of `charset`
+///
+/// # Errors
+/// This function will return an appropriate variant of `std::io::Error`
+/// if the randomness of the operating system can't be read.
+pub fn password(length: usize, charset: Charset) -> Result<String, Error> {
+    let chars = charset.chars();
+    (0..length).map(|_| random_below(chars.len()).map(|i| chars[i] as char)).collect()
+}
+
+/// Function to generate a random passphrase of `words` words
+/// of the bundled wordlist, joined with `separator`
+///
+/// # Errors
+/// This function will return an appropriate variant of `std::io::Error`
+/// if the randomness of the operating system can't be read.
+pub fn passphrase(words: usize, separator: &str) -> Result<String, Error> {
+    let list = wordlist();
+    let words = (0..words).map(|_| random_below(list.len()).map(|i| list[i])).collect::<Result<Vec<_>, _>>()?;
+    Ok(words.join(separator))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_passwords() {
+        let list = wordlist();
+        assert_eq!(list.len(), 2048);
+        assert!(list.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(entropy_bits(list.len(), 6), 66.0);
+
+        for charset in [Charset::Alphanumeric, Charset::Symbols, Charset::Digits, Charset::Hex] {
+            let generated = password(64, charset).unwrap();
+            assert_eq!(generated.len(), 64);
+            assert!(generated.bytes().all(|c| charset.chars().contains(&c)));
+        }
+        assert_eq!(Charset::Digits.chars(), b"0123456789");
+        assert_ne!(password(32, Charset::Alphanumeric).unwrap(), password(32, Charset::Alphanumeric).unwrap());
+
+        let generated = passphrase(6, "-").unwrap();
+        let words: Vec<&str> = generated.split('-').collect();
+        assert_eq!(words.len(), 6);
+        assert!(words.iter().all(|w| list.binary_search(w).is_ok()));
+    }
+}
@@ -0,0 +1,104 @@
+//! Keyfiles:
+//! a file whose content takes part in the key derivation, alone or
+//! along with a password, so that decrypting needs something you have
+//! as well as something you know.
+//!
+//! Any file can be used as a keyfile. The key is an HMAC-SHA256 of the
+//! key derived from the password (or nothing), keyed with the SHA-256
+//! of the content of the keyfile.
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Write};
+use hmac_sha256::{Hash, HMAC};
+
+/// Size of the keyfiles written by `generate`
+pub const KEYFILE_SIZE: usize = 64;
+
+/// Function to write a new keyfile of random bytes to `path`.
+/// On unix, only the owner is allowed to read it
+///
+/// # Errors
+/// This function will return an appropriate variant of
+/// `std::io::Error` if there is any error creating the file.
+///
+/// If a file already exists at `path`,
+/// It will give a `std::io::ErrorKind::AlreadyExists` error.
+pub fn generate(path: &str) -> Result<(), Error> {
+    let mut bytes = [0u8; KEYFILE_SIZE];
+    getrandom::fill(&mut bytes)?;
+
+    let mut options = File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(&bytes)
+}
+
+/// Function to derive the key from a keyfile and the key derived
+/// from the password, if one is used along with the keyfile
+/// Returns the combined key
+///
+/// # Errors
+/// This function will return an appropriate variant of
+/// `std::io::Error` if there is any error reading the keyfile.
+///
+/// If the keyfile is empty,
+/// It will give a `std::io::ErrorKind::InvalidInput` error.
+pub fn combine(key: Option<&[u8; 32]>, keyfile: &str) -> Result<[u8; 32], Error> {
+    let mut file = File::open(keyfile)?;
+    let mut hash = Hash::new();
+    let mut buffer = [0u8; 8192];
+    let mut size = 0;
+    loop {
+        let n = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hash.update(&buffer[..n]);
+        size += n;
+    }
+    if size == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "the keyfile is empty"))
+    }
+
+    let mut mac = HMAC::new(hash.finalize());
+    mac.update(b"cryptile keyfile");
+    if let Some(key) = key {
+        mac.update(key);
+    }
+    Ok(mac.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn keyfile_keys() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("cryptile-keyfile-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let _ = fs::remove_file(&path);
+
+        generate(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap().len(), KEYFILE_SIZE);
+        assert_eq!(generate(&path).err().unwrap().kind(), ErrorKind::AlreadyExists);
+
+        let password = Hash::hash(b"password");
+        let alone = combine(None, &path).unwrap();
+        let both = combine(Some(&password), &path).unwrap();
+        assert_eq!(alone, combine(None, &path).unwrap());
+        assert_ne!(alone, both);
+        assert_ne!(both, password);
+
+        fs::write(&path, b"").unwrap();
+        assert_eq!(combine(None, &path).err().unwrap().kind(), ErrorKind::InvalidInput);
+        fs::remove_file(&path).unwrap();
+    }
+}
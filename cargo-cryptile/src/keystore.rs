@@ -0,0 +1,969 @@
+//! The keystore: the master password and the saved passwords,
+//! kept in `.saved.toml` in the config directory.
+//! Another file can be used instead with `--config` (or `$CRYPTILE_CONFIG`),
+//! or the one of a named profile with `--profile` (or `$CRYPTILE_PROFILE`),
+//! kept in `profiles/<NAME>.toml` in the config directory.
+//!
+//! The keys are encrypted (in the file format of the library) under a key
+//! derived from the master password with PBKDF2-HMAC-SHA256, whose salt and
+//! iteration count are stored next to them. The keystore is unlocked once
+//! per invocation, by asking for the master password.
+//!
+//! Keystores written by older versions hold the keys in plain TOML.
+//! They are encrypted the first time they are unlocked.
+//!
+//! The keystore is locked while it is used, so that processes changing it
+//! one after the other don't lose each other's changes, and it is written
+//! to a temporary file renamed over it, so that it is never left half written.
+//!
+//! The file also holds, unencrypted, the options used when the
+//! command line doesn't give them, in its `[defaults]` table.
+//!
+//! Keys are moved between machines in bundles, written the same
+//! way as the keystore but encrypted with a password of their own.
+
+use cargo_cryptile as cryptile;
+use dialoguer::Password;
+use directories::ProjectDirs;
+use hmac_sha256::HMAC;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+use std::path::PathBuf;
+use std::env;
+use std::sync::OnceLock;
+
+use crate::config::key_of;
+
+const KDF_PBKDF2_SHA256: &str = "pbkdf2-sha256";
+/// Iterations of PBKDF2 for new keystores
+const ITERATIONS: u32 = 600_000;
+/// Most iterations of PBKDF2 accepted, so that a crafted bundle can't keep cryptile busy
+const MAX_ITERATIONS: u32 = 10 * ITERATIONS;
+const SALT_SIZE: usize = 16;
+
+const CONFIG_VAR: &str = "CRYPTILE_CONFIG";
+const PROFILE_VAR: &str = "CRYPTILE_PROFILE";
+
+/// The keystore chosen instead of the default one
+static CHOSEN: OnceLock<PathBuf> = OnceLock::new();
+
+fn config_dir() -> Option<PathBuf> {
+    ProjectDirs::from("com", "cryptile", "cryptile").map(|dirs| dirs.config_dir().to_owned())
+
+    // Linux:   /home/username/.config/cryptile
+    // Windows: C:\Users\Username\AppData\Roaming\cryptile\cryptile
+    // macOS:   /Users/Username/Library/Application Support/com.cryptile.cryptile
+}
+
+pub fn path() -> Option<PathBuf> {
+    if let Some(path) = CHOSEN.get() {
+        return Some(path.clone());
+    }
+    config_dir().map(|dir| dir.join(".saved.toml"))
+}
+
+/// Path of the keystore of a named profile
+fn profile_path(name: &str) -> Result<PathBuf, &'static str> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("Error: A profile name is made of letters, digits, '-' and '_'");
+    }
+    match config_dir() {
+        Some(dir) => Ok(dir.join("profiles").join(format!("{}.toml", name))),
+        None => Err("Error: No valid path for config file"),
+    }
+}
+
+/// Chooses the keystore used for the rest of the invocation:
+/// the file given with `--config`, else the one of the profile given with `--profile`,
+/// else the same from the environment variables, else the default one
+pub fn choose(config: Option<&str>, profile: Option<&str>) -> Result<(), &'static str> {
+    let var = |name| env::var(name).ok().filter(|v| !v.is_empty());
+    let path = match (config, profile) {
+        (Some(config), _) => PathBuf::from(config),
+        (None, Some(profile)) => profile_path(profile)?,
+        (None, None) => match (var(CONFIG_VAR), var(PROFILE_VAR)) {
+            (Some(config), _) => PathBuf::from(config),
+            (None, Some(profile)) => profile_path(&profile)?,
+            (None, None) => return Ok(()),
+        },
+    };
+    let _ = CHOSEN.set(path);
+    Ok(())
+}
+
+/// Whether a keystore was written
+pub fn exists() -> bool {
+    path().is_some_and(|p| p.exists())
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Key {
+    pub key: [u8; 32],
+    pub identifier: String,
+}
+
+/// The keys held by the keystore. Written as is by older versions
+#[derive(Deserialize, Serialize, Default)]
+struct Content {
+    master: Option<[u8; 32]>,
+    keys: Option<Vec<Key>>,
+}
+
+/// Why a keystore or bundle couldn't be unsealed
+#[derive(PartialEq, Debug)]
+enum UnsealError {
+    WrongPassword,
+    Invalid(&'static str),
+}
+
+impl UnsealError {
+    /// The message to show, `wrong_password` when the password doesn't unlock it
+    fn message(self, wrong_password: &'static str) -> &'static str {
+        match self {
+            UnsealError::WrongPassword => wrong_password,
+            UnsealError::Invalid(m) => m,
+        }
+    }
+}
+
+impl From<&'static str> for UnsealError {
+    fn from(m: &'static str) -> Self {
+        UnsealError::Invalid(m)
+    }
+}
+
+/// The keystore as written to disk
+#[derive(Deserialize, Serialize)]
+struct Sealed {
+    kdf: String,
+    iterations: u32,
+    /// Hex encoded
+    salt: String,
+    /// The content, encrypted and hex encoded
+    data: String,
+    /// Kept as is when the keystore is written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    defaults: Option<toml::Value>,
+}
+
+/// Options used when the command line doesn't give them
+#[derive(Deserialize, Serialize, Default, Clone, PartialEq, Debug)]
+pub struct Defaults {
+    /// `prompt`, `master` or `saved:<ID>`
+    pub password: Option<String>,
+    /// Output directory of recursive operations and of extracted archives
+    pub output: Option<String>,
+    pub replace: Option<bool>,
+    /// zstd level files are compressed at when encrypting
+    pub compress: Option<i32>,
+    /// `pow2` or `padme`
+    pub pad: Option<String>,
+}
+
+impl Defaults {
+    pub const KEYS: [&'static str; 5] = ["password", "output", "replace", "compress", "pad"];
+
+    /// Reads the defaults from the keystore file, without unlocking it
+    pub fn read() -> Result<Defaults, &'static str> {
+        let path = match path() {
+            Some(p) => p,
+            None => return Err("Error: No valid path for config file"),
+        };
+        match read_table(&path)?.remove("defaults") {
+            Some(defaults) => match defaults.try_into() {
+                Ok(defaults) => Ok(defaults),
+                Err(_) => Err("Error parsing the defaults of the config file"),
+            },
+            None => Ok(Defaults::default()),
+        }
+    }
+
+    /// Writes the defaults to the keystore file, leaving the keys as they are
+    pub fn write(&self) -> Result<(), &'static str> {
+        let path = match path() {
+            Some(p) => p,
+            None => return Err("Error: No valid path for config file"),
+        };
+        let _lock = lock(&path)?;
+        let mut table = read_table(&path)?;
+        if *self == Defaults::default() {
+            table.remove("defaults");
+        } else {
+            table.insert("defaults".to_owned(), toml::Value::try_from(self).unwrap());
+        }
+        // Written as a value, which puts the keys of the keystore before the table
+        let written = toml::to_string(&toml::Value::Table(table)).map_err(|_| ErrorKind::InvalidData.into())
+            .and_then(|file| write_atomic(&path, file.as_bytes()));
+        if written.is_err() {
+            return Err("Error writing the config file");
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>, &'static str> {
+        Ok(match key {
+            "password" => self.password.clone(),
+            "output" => self.output.clone(),
+            "replace" => self.replace.map(|r| r.to_string()),
+            "compress" => self.compress.map(|l| l.to_string()),
+            "pad" => self.pad.clone(),
+            _ => return Err(unknown_option(key)),
+        })
+    }
+
+    /// Sets an option, or unsets it if `value` is `None`
+    pub fn set(&mut self, key: &str, value: Option<&str>) -> Result<(), &'static str> {
+        match key {
+            "password" => {
+                self.password = match value {
+                    Some(v @ ("prompt" | "master")) => Some(v.to_owned()),
+                    Some(v) if v.strip_prefix("saved:").is_some_and(|id| !id.is_empty()) => Some(v.to_owned()),
+                    Some(_) => return Err("Error: The password option is prompt, master or saved:<ID>"),
+                    None => None,
+                }
+            }
+            "output" => self.output = value.map(str::to_owned),
+            "replace" => {
+                self.replace = match value.map(str::parse) {
+                    Some(Ok(r)) => Some(r),
+                    Some(Err(_)) => return Err("Error: The replace option is true or false"),
+                    None => None,
+                }
+            }
+            "compress" => {
+                self.compress = match value.map(str::parse) {
+                    Some(Ok(level @ 1..=22)) => Some(level),
+                    Some(_) => return Err("Error: The compress option is a level from 1 to 22"),
+                    None => None,
+                }
+            }
+            "pad" => {
+                self.pad = match value {
+                    Some(v @ ("pow2" | "padme")) => Some(v.to_owned()),
+                    Some(_) => return Err("Error: The pad option is pow2 or padme"),
+                    None => None,
+                }
+            }
+            _ => return Err(unknown_option(key)),
+        }
+        Ok(())
+    }
+}
+
+/// Options asked for which the file format doesn't let us choose yet
+const UNSUPPORTED_OPTIONS: [&str; 3] = ["cipher", "kdf", "threads"];
+
+fn unknown_option(key: &str) -> &'static str {
+    if UNSUPPORTED_OPTIONS.contains(&key) {
+        "Error: The cipher, kdf and threads options are not supported yet: \
+        files are always encrypted with AES-256 under a SHA-256 derived key, on one thread"
+    } else {
+        "Error: Unknown option, the options are password, output, replace, compress and pad"
+    }
+}
+
+/// Key the keystore is encrypted with, and how it was derived
+struct Unlock {
+    key: [u8; 32],
+    salt: [u8; SALT_SIZE],
+    iterations: u32,
+}
+
+impl Unlock {
+    fn derive(pass: &str, salt: [u8; SALT_SIZE], iterations: u32) -> Unlock {
+        Unlock { key: pbkdf2(pass.as_bytes(), &salt, iterations), salt, iterations }
+    }
+
+    /// A new key with a new salt, for a new master password
+    fn generate(pass: &str) -> Result<Unlock, &'static str> {
+        let mut salt = [0u8; SALT_SIZE];
+        if getrandom::fill(&mut salt).is_err() {
+            return Err("Error generating a salt for the keystore");
+        }
+        Ok(Unlock::derive(pass, salt, ITERATIONS))
+    }
+}
+
+/// What to do when an imported key has the identifier of a different saved key
+#[derive(PartialEq, Clone, Copy)]
+pub enum Conflict {
+    /// Import nothing
+    Fail,
+    /// Keep the saved key
+    Skip,
+    /// Replace the saved key
+    Overwrite,
+    /// Import the key under a new identifier
+    Rename,
+}
+
+/// What became of a key of a bundle when importing it
+pub enum Imported {
+    Added(String),
+    /// The same key is already saved under the identifier
+    Present(String),
+    Skipped(String),
+    Overwritten(String),
+    Renamed(String, String),
+    Master,
+}
+
+/// Keys exported from a keystore
+pub struct Bundle {
+    content: Content,
+}
+
+impl Bundle {
+    /// Writes the bundle, encrypted with `pass`. Doesn't overwrite existing files
+    pub fn write(&self, path: &str, pass: &str) -> Result<(), &'static str> {
+        let sealed = toml::to_string(&seal(&self.content, &Unlock::generate(pass)?)?).unwrap();
+        let written = File::options().write(true).create_new(true).open(path)
+            .and_then(|mut f| f.write_all(sealed.as_bytes()));
+        match written {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Err("Error: The bundle file already exists"),
+            Err(_) => Err("Error writing the bundle file"),
+        }
+    }
+
+    pub fn read(path: &str, pass: &str) -> Result<Bundle, &'static str> {
+        let file = match fs::read_to_string(Path::new(path)) {
+            Ok(s) => s,
+            Err(_) => return Err("Error reading the bundle file"),
+        };
+        let sealed: Sealed = match toml::from_str(&file) {
+            Ok(s) => s,
+            Err(_) => return Err("Error: Not a bundle of saved passwords"),
+        };
+        match unseal(&sealed, pass) {
+            Ok((content, _)) => Ok(Bundle { content }),
+            Err(e) => Err(e.message("Wrong password for the bundle")),
+        }
+    }
+
+    pub fn has_master(&self) -> bool {
+        self.content.master.is_some()
+    }
+
+    pub fn master(&self) -> Option<[u8; 32]> {
+        self.content.master
+    }
+}
+
+pub struct Keystore {
+    content: Content,
+    /// `None` until a master password is set
+    unlock: Option<Unlock>,
+    /// Whether the keystore was changed since it was read
+    changed: bool,
+    /// Held until the keystore is dropped
+    _lock: Option<File>,
+}
+
+impl Keystore {
+    /// Reads the keystore, asking for the master password to unlock it.
+    /// A keystore which doesn't exist yet is empty
+    pub fn open() -> Result<Keystore, &'static str> {
+        Keystore::unseal().map_err(|e| e.message("Wrong master password"))
+    }
+
+    /// Same as `open`, giving `None` when the master password entered is wrong
+    pub fn open_unless_wrong_master() -> Result<Option<Keystore>, &'static str> {
+        match Keystore::unseal() {
+            Ok(keystore) => Ok(Some(keystore)),
+            Err(UnsealError::WrongPassword) => Ok(None),
+            Err(UnsealError::Invalid(m)) => Err(m),
+        }
+    }
+
+    fn unseal() -> Result<Keystore, UnsealError> {
+        let path = match path() {
+            Some(p) => p,
+            None => {
+                return Err("Error: No valid path for config file\
+                            could be retrieved from\
+                            the operating system".into())
+            }
+        };
+        // The master password is asked for before locking the keystore,
+        // so that a prompt left unanswered doesn't hold up other processes
+        let pass = match toml::from_str::<Sealed>(&read_keystore(&path)?) {
+            Ok(_) => Some(prompt("Enter the master password", false)?),
+            Err(_) => None,
+        };
+        let lock = lock(&path)?;
+        // Read again, as another process may have saved it in the meantime
+        let file = read_keystore(&path)?;
+
+        if let Ok(sealed) = toml::from_str::<Sealed>(&file) {
+            let pass = match pass {
+                Some(p) => p,
+                None => prompt("Enter the master password", false)?,
+            };
+            let (content, unlock) = unseal(&sealed, &pass)?;
+            return Ok(Keystore { content, unlock: Some(unlock), changed: false, _lock: lock });
+        }
+
+        let content: Content = match toml::from_str(&file) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{}", e);
+                return Err("Error parsing the Config File.".into());
+            }
+        };
+        let mut keystore = Keystore { content, unlock: None, changed: false, _lock: lock };
+        keystore.migrate()?;
+        Ok(keystore)
+    }
+
+    /// Encrypts a keystore written by an older version
+    fn migrate(&mut self) -> Result<(), &'static str> {
+        let has_keys = !self.keys().is_empty();
+        let pass = match self.content.master {
+            Some(master) => {
+                eprintln!("The saved passwords are stored unencrypted, \
+                        they will be encrypted with the master password");
+                let pass = prompt("Enter the master password", false)?;
+                if key_of(&pass) != master {
+                    return Err("Wrong master password");
+                }
+                pass
+            }
+            None if has_keys => {
+                eprintln!("The saved passwords are stored unencrypted. \
+                        Set a master password to encrypt them with");
+                let pass = prompt("Enter a master password", true)?;
+                self.content.master = Some(key_of(&pass));
+                pass
+            }
+            None => return Ok(()),
+        };
+
+        self.unlock = Some(Unlock::generate(&pass)?);
+        self.save()?;
+        eprintln!("Successfully Encrypted the saved passwords");
+        Ok(())
+    }
+
+    pub fn master(&self) -> Option<[u8; 32]> {
+        self.content.master
+    }
+
+    pub fn keys(&self) -> &[Key] {
+        self.content.keys.as_deref().unwrap_or(&[])
+    }
+
+    pub fn search(&self, id: &str) -> Option<[u8; 32]> {
+        self.keys().iter().find(|k| k.identifier == id).map(|k| k.key)
+    }
+
+    /// Sets the master password, which the keystore gets encrypted with
+    pub fn set_master(&mut self, pass: &str) -> Result<(), &'static str> {
+        self.unlock = Some(Unlock::generate(pass)?);
+        self.content.master = Some(key_of(pass));
+        self.changed = true;
+        Ok(())
+    }
+
+    pub fn add(&mut self, key: Key) -> Result<(), &'static str> {
+        if self.search(&key.identifier).is_some() {
+            return Err("A saved password with the given identifier already exists");
+        }
+        self.content.keys.get_or_insert_with(Vec::new).push(key);
+        self.changed = true;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: &str) -> Result<(), &'static str> {
+        let keys = self.content.keys.get_or_insert_with(Vec::new);
+        match keys.iter().position(|k| k.identifier == id) {
+            Some(i) => {
+                keys.remove(i);
+                self.changed = true;
+                Ok(())
+            }
+            None => Err("No saved password with the given identifier found"),
+        }
+    }
+
+    pub fn rename(&mut self, id: &str, new_id: &str) -> Result<(), &'static str> {
+        if self.search(new_id).is_some() {
+            return Err("A saved password with the new identifier already exists");
+        }
+        let keys = self.content.keys.get_or_insert_with(Vec::new);
+        match keys.iter_mut().find(|k| k.identifier == id) {
+            Some(key) => {
+                key.identifier = new_id.to_owned();
+                self.changed = true;
+                Ok(())
+            }
+            None => Err("No saved password with the given identifier found"),
+        }
+    }
+
+    /// Bundles the keys with the given identifiers (all of them
+    /// if none are given), and the master if `master` is true
+    pub fn export(&self, ids: &[&str], master: bool) -> Result<Bundle, &'static str> {
+        let keys: Vec<Key> = if ids.is_empty() {
+            self.keys().to_vec()
+        } else {
+            let mut keys = Vec::new();
+            for id in ids {
+                match self.keys().iter().find(|k| k.identifier == *id) {
+                    Some(k) => keys.push(k.clone()),
+                    None => return Err("No saved password with the given identifier found"),
+                }
+            }
+            keys
+        };
+        let master = match (master, self.master()) {
+            (false, _) => None,
+            (true, Some(m)) => Some(m),
+            (true, None) => return Err("No master password set.\n\
+                                        Set one using `cryptile set -m` command"),
+        };
+        if keys.is_empty() && master.is_none() {
+            return Err("No Passwords saved.\n\
+                        Save one using `cryptile set -p` command");
+        }
+        Ok(Bundle { content: Content { master, keys: Some(keys) } })
+    }
+
+    /// Merges the keys of a bundle, and its master if `master` is given:
+    /// the master password of the bundle, which the keystore gets encrypted with.
+    /// With `Conflict::Fail`, nothing is imported if any identifier conflicts
+    pub fn import(&mut self, bundle: Bundle, master: Option<&str>,
+        conflict: Conflict) -> Result<Vec<Imported>, &'static str>
+    {
+        let master = match (master, bundle.content.master) {
+            (Some(pass), Some(bundled)) if self.content.master != Some(bundled) => {
+                if key_of(pass) != bundled {
+                    return Err("Wrong master password for the bundle");
+                }
+                Some(pass)
+            }
+            _ => None,
+        };
+        let keys = bundle.content.keys.unwrap_or_default();
+        let conflicts = keys.iter()
+            .any(|k| matches!(self.search(&k.identifier), Some(saved) if saved != k.key));
+        if conflicts && conflict == Conflict::Fail {
+            return Err("Some saved passwords have the identifiers of different passwords in the bundle.\n\
+                        Use --on-conflict <skip|overwrite|rename> to import them");
+        }
+
+        let mut imported = Vec::new();
+        for key in keys {
+            let id = key.identifier.clone();
+            let outcome = match self.search(&id) {
+                None => Imported::Added(id),
+                Some(saved) if saved == key.key => {
+                    imported.push(Imported::Present(id));
+                    continue;
+                }
+                Some(_) => match conflict {
+                    Conflict::Skip | Conflict::Fail => {
+                        imported.push(Imported::Skipped(id));
+                        continue;
+                    }
+                    Conflict::Overwrite => {
+                        self.remove(&id)?;
+                        Imported::Overwritten(id)
+                    }
+                    Conflict::Rename => {
+                        let new_id = (2..)
+                            .map(|n| format!("{}-{}", id, n))
+                            .find(|new_id| self.search(new_id).is_none())
+                            .unwrap();
+                        self.add(Key { key: key.key, identifier: new_id.clone() })?;
+                        imported.push(Imported::Renamed(id, new_id));
+                        continue;
+                    }
+                },
+            };
+            self.add(key)?;
+            imported.push(outcome);
+        }
+
+        if let Some(pass) = master {
+            self.set_master(pass)?;
+            imported.push(Imported::Master);
+        }
+        Ok(imported)
+    }
+
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    /// Writes the keystore, encrypted
+    pub fn save(&mut self) -> Result<(), &'static str> {
+        let unlock = match &self.unlock {
+            Some(u) => u,
+            None => return Err("No master password set, which the saved passwords are encrypted with"),
+        };
+        let path = match path() {
+            Some(p) => p,
+            None => return Err("Error: No valid path for config file"),
+        };
+        let mut sealed = seal(&self.content, unlock)?;
+        sealed.defaults = read_table(&path)?.remove("defaults");
+        let sealed = toml::to_string(&sealed).unwrap();
+
+        if write_atomic(&path, sealed.as_bytes()).is_err() {
+            return Err("Error writing the config file");
+        }
+        self.changed = false;
+        Ok(())
+    }
+}
+
+/// Reads the keystore file as a plain table. A file which doesn't exist yet is empty
+fn read_table(path: &Path) -> Result<toml::value::Table, &'static str> {
+    match fs::read_to_string(path) {
+        Ok(s) => toml::from_str(&s).map_err(|_| "Error parsing the Config File."),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(toml::value::Table::new()),
+        Err(_) => Err("Error Reading the config file"),
+    }
+}
+
+/// Options creating a file only the owner can read, on unix
+fn private_file() -> OpenOptions {
+    let mut options = File::options();
+    options.write(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+}
+
+/// Takes the lock of the keystore at `path`, waiting for the process holding it.
+/// When the lock file can't be created (e.g. on a read-only medium),
+/// the keystore is used without it, since it can't be written either
+fn lock(path: &Path) -> Result<Option<File>, &'static str> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let file = match fs::create_dir_all(path.parent().unwrap())
+        .and_then(|_| private_file().create(true).truncate(false).open(lock_path))
+    {
+        Ok(f) => f,
+        Err(_) => return Ok(None),
+    };
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => {
+            eprintln!("Waiting for another cryptile process using the keystore...");
+            match file.lock() {
+                Ok(()) => Ok(Some(file)),
+                Err(_) => Err("Error locking the config file"),
+            }
+        }
+        Err(TryLockError::Error(_)) => Err("Error locking the config file"),
+    }
+}
+
+fn read_keystore(path: &Path) -> Result<String, &'static str> {
+    match fs::read_to_string(path) {
+        Ok(s) => Ok(s),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+        Err(_) => Err("Error Reading the config file"),
+    }
+}
+
+/// Writes `path` through a temporary file renamed over it,
+/// so that it holds either the old or the new content whatever happens.
+/// The temporary file has a random name, so that one left behind by a
+/// process which was killed doesn't get in the way
+fn write_atomic(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut suffix = [0u8; 8];
+    getrandom::fill(&mut suffix).map_err(io::Error::other)?;
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{}.tmp", to_hex(&suffix)));
+    let temp = PathBuf::from(temp);
+
+    let written = fs::create_dir_all(path.parent().unwrap())
+        .and_then(|_| private_file().create_new(true).open(&temp))
+        .and_then(|mut f| {
+            f.write_all(content)?;
+            f.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, path));
+    if written.is_err() {
+        _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+pub fn prompt(prompt: &str, confirm: bool) -> Result<String, &'static str> {
+    let mut password = Password::new();
+    password.with_prompt(prompt).allow_empty_password(true);
+    if confirm {
+        password.with_confirmation("Confirm password", "Passwords mismatching");
+    }
+    match password.interact() {
+        Ok(p) if p.is_empty() => Err("Error: No password entered"),
+        Ok(p) => Ok(p),
+        Err(_) => Err("Cannot read from the stdin"),
+    }
+}
+
+fn seal(content: &Content, unlock: &Unlock) -> Result<Sealed, &'static str> {
+    let content = toml::to_string(content).unwrap();
+    let mut data = Vec::new();
+    if cryptile::encrypt_stream(&mut content.as_bytes(), &mut data, &unlock.key).is_err() {
+        return Err("Error encrypting the saved passwords");
+    }
+
+    Ok(Sealed {
+        kdf: KDF_PBKDF2_SHA256.to_owned(),
+        iterations: unlock.iterations,
+        salt: to_hex(&unlock.salt),
+        data: to_hex(&data),
+        defaults: None,
+    })
+}
+
+fn unseal(sealed: &Sealed, pass: &str) -> Result<(Content, Unlock), UnsealError> {
+    if sealed.iterations > MAX_ITERATIONS {
+        return Err(UnsealError::Invalid("Error: The saved passwords ask for too many key derivation iterations"));
+    }
+    let salt = from_hex(&sealed.salt).and_then(|s| s.try_into().ok());
+    let data = from_hex(&sealed.data);
+    let (salt, data) = match (salt, data) {
+        (Some(salt), Some(data)) if sealed.kdf == KDF_PBKDF2_SHA256 && sealed.iterations > 0 => (salt, data),
+        _ => return Err(UnsealError::Invalid("Error parsing the Config File.")),
+    };
+
+    let unlock = Unlock::derive(pass, salt, sealed.iterations);
+    let mut content = Vec::new();
+    if let Err(e) = cryptile::decrypt_stream(&mut data.as_slice(), &mut content, &unlock.key) {
+        return Err(match e.kind() {
+            ErrorKind::InvalidInput => UnsealError::WrongPassword,
+            _ => UnsealError::Invalid("Error decrypting the saved passwords, the config file is corrupted"),
+        });
+    }
+    let content = String::from_utf8(content).ok().and_then(|c| toml::from_str(&c).ok());
+    match content {
+        Some(content) => Ok((content, unlock)),
+        None => Err(UnsealError::Invalid("Error parsing the Config File.")),
+    }
+}
+
+/// PBKDF2-HMAC-SHA256, giving a 32 byte key
+fn pbkdf2(pass: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let keyed = HMAC::new(pass);
+    let mut mac = keyed.clone();
+    mac.update(salt);
+    mac.update(1u32.to_be_bytes());
+    let mut u = mac.finalize();
+
+    let mut key = u;
+    for _ in 1..iterations {
+        let mut mac = keyed.clone();
+        mac.update(u);
+        u = mac.finalize();
+        key.iter_mut().zip(u).for_each(|(k, u)| *k ^= u);
+    }
+    key
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_names() {
+        for name in ["work", "personal-2", "usb_key"] {
+            let path = profile_path(name).unwrap();
+            assert!(path.ends_with(format!("profiles/{}.toml", name)));
+        }
+        for name in ["", "../work", "a/b", "work.toml", "a b"] {
+            assert!(profile_path(name).is_err());
+        }
+    }
+
+    #[test]
+    fn default_options() {
+        let mut defaults = Defaults::default();
+        defaults.set("password", Some("saved:work")).unwrap();
+        defaults.set("replace", Some("true")).unwrap();
+        defaults.set("compress", Some("9")).unwrap();
+        defaults.set("pad", Some("padme")).unwrap();
+        assert_eq!(defaults.get("password").unwrap().as_deref(), Some("saved:work"));
+        assert_eq!(defaults.get("compress").unwrap().as_deref(), Some("9"));
+        assert_eq!(defaults.get("output").unwrap(), None);
+
+        for (key, value) in [("password", "saved:"), ("password", "work"), ("replace", "yes"),
+            ("compress", "23"), ("pad", "zeros"), ("cipher", "aes")]
+        {
+            assert!(defaults.set(key, Some(value)).is_err());
+        }
+        assert!(defaults.get("cipher").unwrap_err().contains("not supported yet"));
+        assert!(defaults.set("color", Some("auto")).unwrap_err().contains("Unknown option"));
+
+        let table = toml::Value::try_from(&defaults).unwrap();
+        assert_eq!(table.try_into::<Defaults>().unwrap(), defaults);
+        for key in Defaults::KEYS {
+            defaults.set(key, None).unwrap();
+        }
+        assert_eq!(defaults, Defaults::default());
+    }
+
+    #[test]
+    fn import_master() {
+        let mut keystore = Keystore {
+            content: Content { master: Some(key_of("old")), keys: None },
+            unlock: Some(Unlock::derive("old", [0; SALT_SIZE], 1)),
+            changed: false,
+            _lock: None,
+        };
+        let bundle = || Bundle { content: Content {
+            master: Some(key_of("new")),
+            keys: Some(vec![Key { key: [1; 32], identifier: "work".to_owned() }]),
+        } };
+
+        assert!(keystore.import(bundle(), Some("old"), Conflict::Fail).is_err());
+        assert!(keystore.search("work").is_none());
+        keystore.import(bundle(), None, Conflict::Fail).unwrap();
+        assert_eq!(keystore.master(), Some(key_of("old")));
+        keystore.import(bundle(), Some("new"), Conflict::Fail).unwrap();
+        assert_eq!(keystore.master(), Some(key_of("new")));
+
+        // Reopened, the keystore is unlocked with the imported master password
+        let sealed = seal(&keystore.content, keystore.unlock.as_ref().unwrap()).unwrap();
+        let sealed: Sealed = toml::from_str(&toml::to_string(&sealed).unwrap()).unwrap();
+        assert!(unseal(&sealed, "old").is_err());
+        let (content, _) = unseal(&sealed, "new").unwrap();
+        assert_eq!(content.master, Some(key_of("new")));
+        assert_eq!(content.keys.unwrap()[0].identifier, "work");
+    }
+
+    #[test]
+    fn atomic_writes() {
+        let dir = std::env::temp_dir().join(format!("cryptile-keystore-{}", std::process::id()));
+        let path = dir.join("keys.toml");
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        // A temporary file left by a killed process
+        fs::write(dir.join(format!("keys.toml.{}.tmp", std::process::id())), b"stale").unwrap();
+        write_atomic(&path, b"newer").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"newer");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        let held = lock(&path).unwrap().unwrap();
+        let other = File::open(dir.join("keys.toml.lock")).unwrap();
+        assert!(matches!(other.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(held);
+        other.try_lock().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pbkdf2_vectors() {
+        assert_eq!(to_hex(&pbkdf2(b"password", b"salt", 1)),
+            "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b");
+        assert_eq!(to_hex(&pbkdf2(b"password", b"salt", 2)),
+            "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43");
+        assert_eq!(to_hex(&pbkdf2(b"password", b"salt", 4096)),
+            "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a");
+    }
+
+    #[test]
+    fn sealed_round_trip() {
+        let content = Content {
+            master: Some(key_of("master")),
+            keys: Some(vec![Key { key: key_of("saved"), identifier: "work".to_owned() }]),
+        };
+        let unlock = Unlock::derive("master", [7; SALT_SIZE], 10);
+        let sealed = seal(&content, &unlock).unwrap();
+        assert!(!sealed.data.contains(&to_hex(&key_of("saved"))));
+
+        let (unsealed, unlock) = unseal(&sealed, "master").unwrap();
+        assert_eq!(unsealed.master, content.master);
+        assert_eq!(unsealed.keys.unwrap()[0].identifier, "work");
+        assert_eq!(unlock.iterations, 10);
+        assert_eq!(unseal(&sealed, "wrong").err(), Some(UnsealError::WrongPassword));
+
+        // A crafted bundle asking for billions of iterations is refused at once
+        let crafted = Sealed { iterations: u32::MAX, ..seal(&content, &unlock).unwrap() };
+        assert!(matches!(unseal(&crafted, "master"), Err(UnsealError::Invalid(_))));
+    }
+
+    #[test]
+    fn key_management() {
+        let mut keystore = Keystore { content: Content::default(), unlock: None, changed: false, _lock: None };
+        keystore.add(Key { key: key_of("a"), identifier: "a".to_owned() }).unwrap();
+        keystore.add(Key { key: key_of("b"), identifier: "b".to_owned() }).unwrap();
+        assert!(keystore.changed());
+        assert!(keystore.add(Key { key: key_of("other"), identifier: "a".to_owned() }).is_err());
+
+        assert!(keystore.rename("a", "b").is_err());
+        keystore.rename("a", "c").unwrap();
+        assert_eq!(keystore.search("c"), Some(key_of("a")));
+        assert_eq!(keystore.search("a"), None);
+
+        keystore.remove("b").unwrap();
+        assert!(keystore.remove("b").is_err());
+        assert_eq!(keystore.keys().len(), 1);
+    }
+
+    #[test]
+    fn bundle_import() {
+        let key = |id: &str, pass: &str| Key { key: key_of(pass), identifier: id.to_owned() };
+        let mut local = Keystore { content: Content::default(), unlock: None, changed: false, _lock: None };
+        local.add(key("same", "same")).unwrap();
+        local.add(key("work", "local")).unwrap();
+
+        let mut remote = Keystore { content: Content::default(), unlock: None, changed: false, _lock: None };
+        remote.content.master = Some(key_of("remote master"));
+        remote.add(key("same", "same")).unwrap();
+        remote.add(key("work", "remote")).unwrap();
+        remote.add(key("new", "new")).unwrap();
+        assert_eq!(remote.export(&["new"], false).unwrap().content.keys.unwrap().len(), 1);
+        assert!(remote.export(&["missing"], false).is_err());
+
+        assert_eq!(remote.export(&[], false).unwrap().content.master, None);
+        let export = || remote.export(&[], true).unwrap();
+
+        assert!(local.import(export(), None, Conflict::Fail).is_err());
+        assert_eq!(local.keys().len(), 2);
+
+        local.import(export(), None, Conflict::Skip).unwrap();
+        assert_eq!(local.search("work"), Some(key_of("local")));
+        assert_eq!(local.search("new"), Some(key_of("new")));
+        assert_eq!(local.master(), None);
+
+        local.import(export(), Some("remote master"), Conflict::Rename).unwrap();
+        assert_eq!(local.search("work-2"), Some(key_of("remote")));
+        assert_eq!(local.master(), Some(key_of("remote master")));
+
+        local.import(export(), None, Conflict::Overwrite).unwrap();
+        assert_eq!(local.search("work"), Some(key_of("remote")));
+        assert_eq!(local.keys().len(), 4);
+    }
+}
@@ -16,15 +16,30 @@
 //! ```
 
 
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::fs::{self, File};
+use std::path::{Path, PathBuf};
 use std::io::{Error, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
 use aes::Aes256;
 use aes::cipher::{
     BlockEncrypt, BlockDecrypt, KeyInit,
     generic_array::GenericArray,
 };
-use hmac_sha256::Hash;
+use hmac_sha256::{Hash, HMAC};
+
+pub mod archive;
+pub mod generate;
+pub mod keyfile;
+pub mod metadata;
+pub mod mnemonic;
+pub mod padding;
+pub mod strength;
+
+pub use metadata::Metadata;
+pub use padding::Padding;
+use metadata::Splitter;
+use padding::{Padder, Unpadder};
 
 enum Stage {
     Encrypt,
@@ -35,13 +50,345 @@ const SMALL_FILE_SIZE_LIMIT: u64 = 26_214_400 * 2;
 const CHUNK_SIZE: usize = 26_214_400;
 pub const FILE_EXTENSION: &str = ".cryptile";
 
+const MAGIC: &[u8; 8] = b"CRYPTILE";
+const HEADER_V1_SIZE: usize = 32;
+const HEADER_SIZE: usize = 40;
+/// Version of the file format written by `encrypt`.
+/// Files written before the header was introduced are reported as version 0
+pub const FORMAT_VERSION: u8 = 2;
+
+const CIPHER_AES256: u8 = 1;
+const KDF_SHA256: u8 = 1;
+const UNKNOWN_SIZE: u64 = u64::MAX;
+const MAX_EXTENSION_SIZE: u32 = 65_536;
+
+/// Every chunk is followed by an HMAC-SHA256 tag
+const FLAG_AUTHENTICATED: u32 = 1;
+/// The plaintext is an archive of several files (see the `archive` module)
+const FLAG_ARCHIVE: u32 = 2;
+/// The plaintext starts with the metadata of the original file (see the `metadata` module)
+const FLAG_METADATA: u32 = 4;
+/// The plaintext is compressed, as described by the compression field of the extension
+const FLAG_COMPRESSED: u32 = 8;
+/// The plaintext is padded to hide its size (see the `padding` module)
+const FLAG_PADDED: u32 = 16;
+/// The key was derived with a keyfile (see the `keyfile` module)
+const FLAG_KEYFILE: u32 = 32;
+const KNOWN_FLAGS: u32 = FLAG_AUTHENTICATED | FLAG_ARCHIVE | FLAG_METADATA
+    | FLAG_COMPRESSED | FLAG_PADDED | FLAG_KEYFILE;
+
+/// Extension field describing the compression: algorithm (1) | level (1, signed)
+const FIELD_COMPRESSION: u8 = 1;
+const COMPRESSION_ZSTD: u8 = 1;
+/// Extension field naming the padding scheme (1)
+const FIELD_PADDING: u8 = 2;
+/// Extension field holding the fingerprint of the key (see `fingerprint`)
+const FIELD_FINGERPRINT: u8 = 3;
+/// zstd compression level used when none is chosen
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+/// Amount of data compressed up front to decide whether compressing is worth it
+const COMPRESSION_SAMPLE_SIZE: usize = 1_048_576;
+const TAG_SIZE: usize = 32;
+/// Size of the fingerprint of a key
+pub const FINGERPRINT_SIZE: usize = 8;
+
+/// Header written at the start of every encrypted file (format version >= 1)
+///
+/// Layout (little endian):
+/// magic (8) | version (1) | cipher (1) | kdf (1) | key slots (1) |
+/// chunk size (4) | plaintext size (8) | creation time (8) |
+/// flags (4) | extension size (4) | extension (extension size)
+///
+/// Version 1 headers end before the flags.
+/// The extension is a sequence of fields: type (1) | length (2) | value
+///
+/// The header is followed by the key slots, each one being the
+/// 32 byte hash of the key encrypted with the key itself
+struct Header {
+    version: u8,
+    cipher: u8,
+    kdf: u8,
+    key_slots: u8,
+    chunk_size: u32,
+    plaintext_size: u64,
+    created: u64,
+    flags: u32,
+    extension: Vec<u8>,
+}
+
+impl Header {
+    fn new(plaintext_size: u64, flags: u32) -> Header {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Header {
+            version: FORMAT_VERSION,
+            cipher: CIPHER_AES256,
+            kdf: KDF_SHA256,
+            key_slots: 1,
+            chunk_size: CHUNK_SIZE as u32,
+            plaintext_size,
+            created,
+            flags: FLAG_AUTHENTICATED | flags,
+            extension: Vec::new(),
+        }
+    }
+
+    fn legacy() -> Header {
+        Header {
+            version: 0,
+            cipher: CIPHER_AES256,
+            kdf: KDF_SHA256,
+            key_slots: 1,
+            chunk_size: CHUNK_SIZE as u32,
+            plaintext_size: UNKNOWN_SIZE,
+            created: 0,
+            flags: 0,
+            extension: Vec::new(),
+        }
+    }
+
+    fn has(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    fn add_field(&mut self, kind: u8, value: &[u8]) {
+        self.extension.push(kind);
+        self.extension.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        self.extension.extend_from_slice(value);
+    }
+
+    /// Finds a field of the extension. Fields of unknown types are skipped,
+    /// the flags tell which fields are needed to read the file
+    fn field(&self, kind: u8) -> Result<Option<&[u8]>, Error> {
+        let mut rest = self.extension.as_slice();
+        while !rest.is_empty() {
+            if rest.len() < 3 {
+                return Err(Error::from(ErrorKind::InvalidData))
+            }
+            let length = u16::from_le_bytes([rest[1], rest[2]]) as usize;
+            if rest.len() < 3 + length {
+                return Err(Error::from(ErrorKind::InvalidData))
+            }
+            if rest[0] == kind {
+                return Ok(Some(&rest[3..3 + length]))
+            }
+            rest = &rest[3 + length..];
+        }
+        Ok(None)
+    }
+
+    /// The zstd compression level, if the plaintext is compressed
+    fn compression_level(&self) -> Result<Option<i32>, Error> {
+        if !self.has(FLAG_COMPRESSED) {
+            return Ok(None)
+        }
+        match self.field(FIELD_COMPRESSION)? {
+            Some(&[COMPRESSION_ZSTD, level]) => Ok(Some(level as i8 as i32)),
+            _ => Err(Error::from(ErrorKind::Unsupported)),
+        }
+    }
+
+    /// The padding scheme, if the plaintext is padded
+    fn padding(&self) -> Result<Option<Padding>, Error> {
+        if !self.has(FLAG_PADDED) {
+            return Ok(None)
+        }
+        match self.field(FIELD_PADDING)? {
+            Some(&[id]) => Padding::from_id(id).map(Some).ok_or(Error::from(ErrorKind::Unsupported)),
+            _ => Err(Error::from(ErrorKind::Unsupported)),
+        }
+    }
+
+    /// The fingerprint of the key, if it was recorded
+    fn fingerprint(&self) -> Result<Option<[u8; FINGERPRINT_SIZE]>, Error> {
+        match self.field(FIELD_FINGERPRINT)? {
+            None => Ok(None),
+            Some(value) => value.try_into()
+                .map(Some)
+                .map_err(|_| Error::from(ErrorKind::InvalidData)),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        if self.version == 0 {
+            return Vec::new()
+        }
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.extension.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&[self.version, self.cipher, self.kdf, self.key_slots]);
+        bytes.extend_from_slice(&self.chunk_size.to_le_bytes());
+        bytes.extend_from_slice(&self.plaintext_size.to_le_bytes());
+        bytes.extend_from_slice(&self.created.to_le_bytes());
+        if self.version >= 2 {
+            bytes.extend_from_slice(&self.flags.to_le_bytes());
+            bytes.extend_from_slice(&(self.extension.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&self.extension);
+        }
+        bytes
+    }
+
+    fn size(&self) -> u64 {
+        self.to_bytes().len() as u64
+    }
+
+    fn write<W: Write>(&self, file: &mut W) -> Result<(), Error> {
+        file.write_all(&self.to_bytes())
+    }
+
+    /// Reads the header from the start of the file.
+    /// For files without a header (written by older versions),
+    /// a legacy header is returned along with the bytes already
+    /// consumed, which belong to the body and have to be read again
+    fn read<R: Read>(file: &mut R) -> Result<(Header, Vec<u8>), Error> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        let n = read_full(file, &mut bytes[..HEADER_V1_SIZE])?;
+
+        if n < MAGIC.len() || &bytes[..8] != MAGIC {
+            return Ok((Header::legacy(), bytes[..n].to_vec()))
+        }
+        if n < HEADER_V1_SIZE {
+            return Err(Error::from(ErrorKind::UnexpectedEof))
+        }
+
+        let mut header = Header {
+            version: bytes[8],
+            cipher: bytes[9],
+            kdf: bytes[10],
+            key_slots: bytes[11],
+            chunk_size: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            plaintext_size: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            created: u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
+            flags: 0,
+            extension: Vec::new(),
+        };
+
+        if header.version > FORMAT_VERSION
+            || header.cipher != CIPHER_AES256
+            || header.kdf != KDF_SHA256
+        {
+            return Err(Error::from(ErrorKind::Unsupported))
+        }
+        // A chunk is read whole, so a larger size would only make us allocate
+        // whatever a crafted header asks for: no version writes larger chunks
+        if header.chunk_size == 0 || !header.chunk_size.is_multiple_of(16)
+            || header.chunk_size as usize > CHUNK_SIZE
+        {
+            return Err(Error::from(ErrorKind::InvalidData))
+        }
+
+        if header.version >= 2 {
+            file.read_exact(&mut bytes[HEADER_V1_SIZE..])?;
+            header.flags = u32::from_le_bytes(bytes[32..36].try_into().unwrap());
+            let extension_size = u32::from_le_bytes(bytes[36..40].try_into().unwrap());
+
+            if header.flags & !KNOWN_FLAGS != 0 || extension_size > MAX_EXTENSION_SIZE {
+                return Err(Error::from(ErrorKind::Unsupported))
+            }
+            header.extension = vec![0u8; extension_size as usize];
+            file.read_exact(&mut header.extension)?;
+            header.compression_level()?;
+            header.padding()?;
+            header.fingerprint()?;
+        }
+
+        Ok((header, Vec::new()))
+    }
+}
+
+/// Computes the authentication tags of the chunks of a file.
+/// Each tag covers the header, the position of the chunk and
+/// whether it is the last one, so that chunks can't be
+/// modified, reordered, dropped or truncated undetected
+struct ChunkAuth {
+    key: [u8; 32],
+    header: Vec<u8>,
+}
+
+impl ChunkAuth {
+    fn new(key: &[u8; 32], header: &Header) -> ChunkAuth {
+        ChunkAuth {
+            key: HMAC::mac(b"cryptile chunk authentication", key),
+            header: header.to_bytes(),
+        }
+    }
+
+    fn tag(&self, index: u64, last: bool, blocks: &[[u8; 16]]) -> [u8; 32] {
+        let mut mac = HMAC::new(self.key);
+        mac.update(&self.header);
+        mac.update(index.to_le_bytes());
+        mac.update([last as u8]);
+        for block in blocks {
+            mac.update(block);
+        }
+        mac.finalize()
+    }
+}
+
+fn tags_equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn corrupted_chunk(index: u64) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("chunk {} is corrupted", index))
+}
+
+/// Information about an encrypted file which can be read without the key
+pub struct FileInfo {
+    /// Format version of the file. 0 for files written before headers were introduced
+    pub version: u8,
+    pub cipher: &'static str,
+    pub kdf: &'static str,
+    pub key_slots: u8,
+    /// Whether every chunk carries an authentication tag
+    pub authenticated: bool,
+    /// Whether the file is an archive of several files
+    pub archive: bool,
+    /// Whether the metadata of the original file is stored
+    pub metadata: bool,
+    /// The zstd compression level, if the content is compressed
+    pub compression_level: Option<i32>,
+    /// The padding scheme, if the size of the content is hidden
+    pub padding: Option<Padding>,
+    /// Whether a keyfile is needed to decrypt the file
+    pub keyfile: bool,
+    /// The fingerprint of the key, if it was recorded
+    pub fingerprint: Option<[u8; FINGERPRINT_SIZE]>,
+    /// Size of the chunks in which large files are processed
+    pub chunk_size: Option<u32>,
+    /// Size of the original file, if recorded
+    pub plaintext_size: Option<u64>,
+    /// Creation time as seconds since the unix epoch, if recorded
+    pub created: Option<u64>,
+    /// Total size of the encrypted file
+    pub file_size: u64,
+}
+
+/// Reads until the buffer is full or the end of the input is reached.
+/// Unlike a single `read` call, a short count always means end of input,
+/// even when reading from pipes
+fn read_full<R: Read>(file: &mut R, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut total = 0;
+    while total < buf.len() {
+        match file.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(total)
+}
+
 
 fn cipher_init(key: &[u8; 32]) -> Aes256 {
     let key = GenericArray::from(*key);
     Aes256::new(&key)
 }
 
-fn encrypt_chunk_serially(blocks: &mut Vec<[u8; 16]>, cipher: &Aes256) {
+fn encrypt_chunk_serially(blocks: &mut [[u8; 16]], cipher: &Aes256) {
     for byte_block in blocks.iter_mut() {
         let mut block = GenericArray::from(byte_block.to_owned());
 
@@ -55,7 +402,7 @@ fn encrypt_chunk_serially(blocks: &mut Vec<[u8; 16]>, cipher: &Aes256) {
     }
 }
 
-fn decrypt_chunk_serially(blocks: &mut Vec<[u8; 16]>, cipher: &Aes256) {
+fn decrypt_chunk_serially(blocks: &mut [[u8; 16]], cipher: &Aes256) {
     for byte_block in blocks.iter_mut() {
         let mut block = GenericArray::from(byte_block.to_owned());
 
@@ -125,32 +472,141 @@ fn decrypt_chunk_serially(blocks: &mut Vec<[u8; 16]>, cipher: &Aes256) {
 //     }
 // }
 
-fn encrypt_small_file(r_file: &mut File, cipher: &Aes256, w_file: &mut File) -> Result<(), Error> {
-    let mut blocks = read_entire_as_blocks(r_file, Stage::Encrypt)?;
-    encrypt_chunk_serially(&mut blocks, &cipher);
-    write_entire_from_blocks(w_file, &blocks, Stage::Encrypt)?;
-
-    Ok(())
-}
-
-fn encrypt_large_file(r_file: &mut File, cipher: &Aes256, w_file: &mut File) -> Result<(), Error> {
-    println!("Encrypting large file in parts...");
+fn encrypt_chunks<R: Read, W: Write>(r_file: &mut R, cipher: &Aes256, auth: &ChunkAuth, w_file: &mut W) -> Result<(), Error> {
+    let mut index = 0u64;
     loop {
         let (mut blocks, cont) = read_chunk_as_blocks(r_file, Stage::Encrypt)?;
-        encrypt_chunk_serially(&mut blocks, &cipher);
+        encrypt_chunk_serially(&mut blocks, cipher);
         write_entire_from_blocks(w_file, &blocks, Stage::Encrypt)?;
+        w_file.write_all(&auth.tag(index, !cont, &blocks))?;
 
         if !cont {
             break
         }
+        index += 1;
     }
-        
+
     Ok(())
 }
 
-fn decrypt_small_file(r_file: &mut File, cipher: &Aes256, w_file: &mut File) -> Result<(), Error> {
+/// The decrypted blocks of a chunk, along with the padding
+/// of its last block if it is the last chunk
+type Chunk = (Vec<[u8; 16]>, Option<u8>);
+
+/// Reads the chunks of an authenticated body one at a time,
+/// checking the tag of each before decrypting it
+struct ChunkReader<'a, R: Read> {
+    reader: &'a mut R,
+    cipher: &'a Aes256,
+    auth: &'a ChunkAuth,
+    buffer: Vec<u8>,
+    peeked: Option<u8>,
+    index: u64,
+    done: bool,
+}
+
+impl<'a, R: Read> ChunkReader<'a, R> {
+    fn new(reader: &'a mut R, cipher: &'a Aes256, auth: &'a ChunkAuth, chunk_size: usize) -> ChunkReader<'a, R> {
+        ChunkReader {
+            reader,
+            cipher,
+            auth,
+            buffer: vec![0u8; chunk_size + TAG_SIZE],
+            peeked: None,
+            index: 0,
+            done: false,
+        }
+    }
+
+    /// Returns the next chunk, or `None` after the last one
+    fn next_chunk(&mut self) -> Result<Option<Chunk>, Error> {
+        if self.done {
+            return Ok(None)
+        }
+        let index = self.index;
+
+        let mut n = 0;
+        if let Some(byte) = self.peeked.take() {
+            self.buffer[0] = byte;
+            n = 1;
+        }
+        n += read_full(self.reader, &mut self.buffer[n..])?;
+
+        // A full chunk is the last one only if nothing follows it
+        let mut last = true;
+        if n == self.buffer.len() {
+            let mut next = [0u8; 1];
+            if read_full(self.reader, &mut next)? == 1 {
+                self.peeked = Some(next[0]);
+                last = false;
+            }
+        }
+
+        if n < 16 + TAG_SIZE || !(n - TAG_SIZE).is_multiple_of(16) {
+            return Err(corrupted_chunk(index))
+        }
+        let (data, tag) = self.buffer[..n].split_at(n - TAG_SIZE);
+        let mut blocks: Vec<[u8; 16]> = data.chunks_exact(16)
+            .map(|block| block.try_into().unwrap())
+            .collect();
+
+        if !tags_equal(&self.auth.tag(index, last, &blocks), tag) {
+            return Err(corrupted_chunk(index))
+        }
+        decrypt_chunk_serially(&mut blocks, self.cipher);
+
+        self.index += 1;
+        if !last {
+            return Ok(Some((blocks, None)))
+        }
+        self.done = true;
+        let padding = blocks.last().unwrap()[15];
+        if padding == 0 {
+            return Err(corrupted_chunk(index))
+        }
+        Ok(Some((blocks, Some(padding))))
+    }
+}
+
+/// The payload of an authenticated body, decrypted a chunk at a time as it is read
+struct PayloadReader<'a, R: Read> {
+    chunks: ChunkReader<'a, R>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> Read for PayloadReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.position == self.chunk.len() {
+            match self.chunks.next_chunk()? {
+                Some((blocks, padding)) => {
+                    self.chunk.clear();
+                    self.position = 0;
+                    write_entire_from_blocks(&mut self.chunk, &blocks, Stage::Decrypt(padding))?;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+fn decrypt_chunks<R: Read, W: Write>(r_file: &mut R, cipher: &Aes256, auth: &ChunkAuth,
+    chunk_size: usize, w_file: &mut W) -> Result<(), Error>
+{
+    let mut chunks = ChunkReader::new(r_file, cipher, auth, chunk_size);
+    while let Some((blocks, padding)) = chunks.next_chunk()? {
+        write_entire_from_blocks(w_file, &blocks, Stage::Decrypt(padding))?;
+    }
+    Ok(())
+}
+
+fn decrypt_small_file<R: Read, W: Write>(r_file: &mut R, cipher: &Aes256, w_file: &mut W) -> Result<(), Error> {
     let mut blocks = read_entire_as_blocks(r_file, Stage::Decrypt(None))?;
-    decrypt_chunk_serially(&mut blocks, &cipher);
+    decrypt_chunk_serially(&mut blocks, cipher);
     let last_block = blocks.last();
     if let Some(block) = last_block {
         if let Some(padding) = block.last() {
@@ -166,11 +622,10 @@ fn decrypt_small_file(r_file: &mut File, cipher: &Aes256, w_file: &mut File) ->
     Ok(())
 }
 
-fn decrypt_large_file(r_file: &mut File, cipher: &Aes256, w_file: &mut File) -> Result<(), Error> {
-    println!("Decrypting large file in parts...");
+fn decrypt_large_file<R: Read, W: Write>(r_file: &mut R, cipher: &Aes256, w_file: &mut W) -> Result<(), Error> {
     loop {
         let (mut blocks, cont) = read_chunk_as_blocks(r_file, Stage::Decrypt(None))?;
-        decrypt_chunk_serially(&mut blocks, &cipher);
+        decrypt_chunk_serially(&mut blocks, cipher);
 
         if !cont {
             let last_block = blocks.last();
@@ -192,7 +647,7 @@ fn decrypt_large_file(r_file: &mut File, cipher: &Aes256, w_file: &mut File) ->
     Ok(())
 }
 
-fn hash_encrypt_write(hash: [u8; 32], cipher: &Aes256, file: &mut File) -> Result<(), Error> {
+fn hash_encrypt_write<W: Write>(hash: [u8; 32], cipher: &Aes256, file: &mut W) -> Result<(), Error> {
     let mut temp_block = [0u8; 16];
     for (i, byte) in hash[0..16].iter().enumerate() {
         temp_block[i] = *byte;
@@ -208,16 +663,16 @@ fn hash_encrypt_write(hash: [u8; 32], cipher: &Aes256, file: &mut File) -> Resul
     cipher.encrypt_block(&mut block1);
     cipher.encrypt_block(&mut block2);
 
-    file.write(&block1)?;
-    file.write(&block2)?;
+    file.write_all(&block1)?;
+    file.write_all(&block2)?;
     Ok(())
 }
 
-fn hash_read_decrypt(cipher: &Aes256, file: &mut File) -> Result<[u8; 32], Error> {
+fn hash_read_decrypt<R: Read>(cipher: &Aes256, file: &mut R) -> Result<[u8; 32], Error> {
     let mut block1 = [0u8; 16];
     let mut block2 = [0u8; 16];
-    file.read(&mut block1)?;
-    file.read(&mut block2)?;
+    file.read_exact(&mut block1)?;
+    file.read_exact(&mut block2)?;
 
     let mut block1 = GenericArray::from(block1);
     let mut block2 = GenericArray::from(block2);
@@ -239,50 +694,56 @@ fn hash_read_decrypt(cipher: &Aes256, file: &mut File) -> Result<[u8; 32], Error
     Ok(res)
 }
 
-fn read_entire_as_blocks(file: &mut File, stage: Stage) -> Result<Vec<[u8; 16]>, Error> {
+/// Reads the next block of the input and whether the input has ended.
+/// When encrypting, the final partial (or empty) block is padded
+fn read_block<R: Read>(file: &mut R, stage: &Stage) -> Result<(Option<[u8; 16]>, bool), Error> {
+    let mut block = [0u8; 16];
+    match read_full(file, &mut block)? {
+        16 => Ok((Some(block), false)),
+        b => {
+            if let Stage::Encrypt = stage {
+                let padding = (16 - b) as u8;
+                block[16 - 1] = padding;
+                return Ok((Some(block), true))
+            }
+            if b > 0 {
+                return Err(Error::from(ErrorKind::UnexpectedEof))
+            }
+            Ok((None, true))
+        }
+    }
+}
+
+fn read_entire_as_blocks<R: Read>(file: &mut R, stage: Stage) -> Result<Vec<[u8; 16]>, Error> {
     let mut bytes = Vec::new();
 
     loop {
-        let mut block = [0u8; 16];
-        match file.read(&mut block) {
-            Ok(b) if b < 16 => {
-                if let Stage::Encrypt = stage {
-                    let padding = (16 - b) as u8;
-                    block[16 - 1] = padding;
-                    bytes.push(block);
-                }
-                break
-            },
-            Err(e) => return Err(Error::from(e)),
-            Ok(_) => ()
+        let (block, end) = read_block(file, &stage)?;
+        if let Some(block) = block {
+            bytes.push(block);
+        }
+        if end {
+            break
         }
-        bytes.push(block);
     }
     
     Ok(bytes)
 }
 
-fn read_chunk_as_blocks(file: &mut File, stage: Stage) -> Result<(Vec<[u8; 16]>, bool), Error> {
+fn read_chunk_as_blocks<R: Read>(file: &mut R, stage: Stage) -> Result<(Vec<[u8; 16]>, bool), Error> {
     let mut bytes = Vec::new();
     let n_blocks = CHUNK_SIZE / 16;
     let mut cont = true;
 
     loop {
-        let mut block = [0u8; 16];
-        match file.read(&mut block) {
-            Ok(b) if b < 16 => {
-                if let Stage::Encrypt = stage {
-                    let padding = (16 - b) as u8;
-                    block[16 - 1] = padding;
-                    bytes.push(block);
-                }
-                cont = false;
-                break
-            },
-            Err(e) => return Err(Error::from(e)),
-            Ok(_) => ()
+        let (block, end) = read_block(file, &stage)?;
+        if let Some(block) = block {
+            bytes.push(block);
+        }
+        if end {
+            cont = false;
+            break
         }
-        bytes.push(block);
         if bytes.len() >= n_blocks {
             break
         }
@@ -291,7 +752,7 @@ fn read_chunk_as_blocks(file: &mut File, stage: Stage) -> Result<(Vec<[u8; 16]>,
     Ok((bytes, cont))
 }
 
-fn write_entire_from_blocks(file: &mut File, blocks: &Vec<[u8; 16]>, stage: Stage) -> Result<(), Error> {
+fn write_entire_from_blocks<W: Write>(file: &mut W, blocks: &[[u8; 16]], stage: Stage) -> Result<(), Error> {
 
     let mut blocks = blocks.iter().peekable();
     while let Some(block) = blocks.next() {
@@ -301,21 +762,18 @@ fn write_entire_from_blocks(file: &mut File, blocks: &Vec<[u8; 16]>, stage: Stag
                     return Err(Error::from(ErrorKind::InvalidInput));
                 }
                 let t = (16 - padding) as usize;
-                if let Err(e) = file.write(&block[..t]) {
-                    return Err(e)
-                }
+                file.write_all(&block[..t])?;
                 break
             }
         }
-        if let Err(e) = file.write(block) {
-            return Err(e)
-        }
+        file.write_all(block)?;
     }
 
     Ok(())
 }
 
 /// Function to encrypt a file using a 32-bit key
+/// The name, permissions and times of the file are stored along with it
 /// Returns Result type 
 /// 
 /// # Errors
@@ -323,24 +781,184 @@ fn write_entire_from_blocks(file: &mut File, blocks: &Vec<[u8; 16]>, stage: Stag
 /// `std::io::Error` if there is any error reading the file
 /// or creating the encrypted file
 pub fn encrypt(filename: &str, key: &[u8; 32]) -> Result<(), Error> {
-    let key_hash = Hash::hash(key);
-    let cipher = cipher_init(key);
+    encrypt_with(filename, key, &FileOptions::default()).map(|_| ())
+}
+
+/// Function to encrypt a file using a 32-bit key,
+/// choosing which metadata of the file is stored along with it
+/// and whether the encrypted file gets a random name
+/// Returns the name of the encrypted file
+/// 
+/// # Errors
+/// This function gives the same errors as `encrypt`
+/// 
+/// If names are to be obfuscated without storing the metadata,
+/// It will give a `std::io::ErrorKind::InvalidInput` error.
+pub fn encrypt_with(filename: &str, key: &[u8; 32], options: &FileOptions) -> Result<String, Error> {
     let new_file_name = filename.to_owned() + FILE_EXTENSION;
+    encrypt_to_output(filename, &new_file_name, key, options)
+}
 
-    let mut reader = File::open(filename)?;
+/// Encrypts a file to `output`, or when obfuscating names, to a random
+/// name in the directory of `output`. Returns the name of the encrypted file
+fn encrypt_to_output(filename: &str, output: &str, key: &[u8; 32],
+    options: &FileOptions) -> Result<String, Error>
+{
+    if !options.obfuscate {
+        encrypt_to_path_with(filename, output, key, options)?;
+        return Ok(output.to_owned())
+    }
+    if !options.metadata {
+        return Err(Error::new(ErrorKind::InvalidInput, "obfuscated names require storing the metadata"))
+    }
+
+    let output = random_path(output)?;
+    if let Err(e) = encrypt_to_path_with(filename, &output, key, options) {
+        delete(&output);
+        return Err(e)
+    }
+    Ok(output)
+}
+
+/// Path in the directory of `path` with a random name,
+/// revealing nothing about the original file
+fn random_path(path: &str) -> Result<String, Error> {
+    loop {
+        let mut bytes = [0u8; 16];
+        getrandom::fill(&mut bytes)?;
+        let name: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+        let path = Path::new(path).with_file_name(name + FILE_EXTENSION);
+        if !path.exists() {
+            return path_str(&path).map(|p| p.to_owned())
+        }
+    }
+}
+
+/// Function to encrypt a file using a 32-bit key,
+/// writing the encrypted file to `output` instead of `<filename>.cryptile`
+/// Returns Result type
+/// 
+/// # Errors
+/// This function gives the same errors as `encrypt`
+pub fn encrypt_to_path(filename: &str, output: &str, key: &[u8; 32]) -> Result<(), Error> {
+    encrypt_to_path_with(filename, output, key, &FileOptions::default())
+}
+
+/// Function to encrypt a file to `output`,
+/// choosing which metadata of the file is stored along with it.
+/// `output` is used as is, even when obfuscating names
+/// Returns Result type
+/// 
+/// # Errors
+/// This function gives the same errors as `encrypt`
+pub fn encrypt_to_path_with(filename: &str, output: &str, key: &[u8; 32],
+    options: &FileOptions) -> Result<(), Error>
+{
+    let metadata = match options.metadata {
+        true => Some(Metadata::read(filename, options.xattrs)?),
+        false => None,
+    };
+    encrypt_to_path_with_metadata(filename, output, key, metadata.as_ref(), options)
+}
+
+/// Function to encrypt a file to `output`, storing the given metadata
+/// instead of the file's own (or none at all)
+/// Returns Result type
+/// 
+/// # Errors
+/// This function gives the same errors as `encrypt`
+pub fn encrypt_to_path_with_metadata(filename: &str, output: &str, key: &[u8; 32],
+    metadata: Option<&Metadata>, options: &FileOptions) -> Result<(), Error>
+{
+    let reader = File::open(filename)?;
     let size = reader.metadata()?.len();
-    let mut writer = File::create(&new_file_name)?;
+    let mut writer = File::create(output)?;
+
+    if size >= SMALL_FILE_SIZE_LIMIT {
+        eprintln!("Encrypting large file in parts...");
+    }
+    match metadata {
+        Some(metadata) => {
+            let mut reader = io::Cursor::new(metadata.to_bytes()).chain(reader);
+            encrypt_to(&mut reader, &mut writer, key, size, FLAG_METADATA, options)
+        }
+        None => encrypt_to(&mut { reader }, &mut writer, key, size, 0, options),
+    }
+}
 
-    hash_encrypt_write(key_hash, &cipher, &mut writer)?;
+/// Function to encrypt everything read from `reader` using a 32-bit key,
+/// writing the encrypted file to `writer`.
+/// Useful for pipes, where the size of the input isn't known in advance
+/// Returns Result type
+/// 
+/// # Errors
+/// This function will return an appropriate variant of
+/// `std::io::Error` if there is any error reading or writing
+pub fn encrypt_stream<R: Read, W: Write>(reader: &mut R, writer: &mut W, key: &[u8; 32]) -> Result<(), Error> {
+    encrypt_stream_with(reader, writer, key, &FileOptions { metadata: false, ..FileOptions::default() })
+}
 
-    if size < SMALL_FILE_SIZE_LIMIT {
-        encrypt_small_file(&mut reader, &cipher, &mut writer)?;
+/// Function to encrypt everything read from `reader` like `encrypt_stream`,
+/// compressing and padding it if `options` ask for it.
+/// There being no file, no metadata is stored
+/// Returns Result type
+/// 
+/// # Errors
+/// This function gives the same errors as `encrypt_stream`
+pub fn encrypt_stream_with<R: Read, W: Write>(reader: &mut R, writer: &mut W, key: &[u8; 32],
+    options: &FileOptions) -> Result<(), Error>
+{
+    encrypt_to(reader, writer, key, UNKNOWN_SIZE, 0, options)?;
+    writer.flush()
+}
+
+/// Encrypts everything read from `reader`. Only the compression, padding,
+/// keyfile and fingerprint of `options` are used, the caller handles the metadata
+fn encrypt_to<R: Read, W: Write>(reader: &mut R, writer: &mut W, key: &[u8; 32],
+    size: u64, flags: u32, options: &FileOptions) -> Result<(), Error>
+{
+    let key_hash = Hash::hash(key);
+    let cipher = cipher_init(key);
+    // A recorded size would defeat the padding
+    let size = if options.padding.is_some() { UNKNOWN_SIZE } else { size };
+    let mut header = Header::new(size, flags);
+    if options.keyfile {
+        header.flags |= FLAG_KEYFILE;
     }
-    else {
-        encrypt_large_file(&mut reader, &cipher, &mut writer)?;
+    if options.fingerprint {
+        header.add_field(FIELD_FINGERPRINT, &fingerprint(key));
     }
+    let mut reader: Box<dyn Read + '_> = Box::new(reader);
 
-    Ok(())
+    if let Some(level) = options.compression {
+        if !zstd::compression_level_range().contains(&level) || level < i8::MIN as i32 {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid compression level"))
+        }
+
+        // The start of the data decides whether it is compressed at all
+        let mut sample = vec![0u8; COMPRESSION_SAMPLE_SIZE];
+        let n = read_full(&mut reader, &mut sample)?;
+        sample.truncate(n);
+        let compressed_size = zstd::bulk::compress(&sample, level)?.len();
+        reader = Box::new(io::Cursor::new(sample).chain(reader));
+
+        if compressed_size * 20 < n * 19 {
+            header.flags |= FLAG_COMPRESSED;
+            header.add_field(FIELD_COMPRESSION, &[COMPRESSION_ZSTD, level as i8 as u8]);
+            reader = Box::new(zstd::stream::read::Encoder::new(reader, level)?);
+        }
+    }
+
+    if let Some(padding) = options.padding {
+        header.flags |= FLAG_PADDED;
+        header.add_field(FIELD_PADDING, &[padding.id()]);
+        reader = Box::new(Padder::new(reader, padding));
+    }
+
+    header.write(writer)?;
+    hash_encrypt_write(key_hash, &cipher, writer)?;
+    encrypt_chunks(&mut reader, &cipher, &ChunkAuth::new(key, &header), writer)
 }
 
 /// Function to decrypt a previously ecrypted file using the `encrypt` function
@@ -353,40 +971,627 @@ pub fn encrypt(filename: &str, key: &[u8; 32]) -> Result<(), Error> {
 /// 
 /// If the file given as the arguement isn't a file encrypted
 /// with this tool (i.e. not ending with .cryptile),
+/// or was encrypted by a newer version of this tool,
 /// It will give a `std::io::ErrorKind::Unsupported` error.
 /// 
 /// If the key given as the arguement isn't the key used to
 /// encrypt the file and can't be used as a decryption key,
 /// It will give a `std::io::ErrorKind::InvalidInput` error.
 pub fn decrypt(filename: &str, key: &[u8; 32]) -> Result<(), Error> {
-    if !filename.ends_with(FILE_EXTENSION) {
-        return Err(Error::from(ErrorKind::Unsupported))
+    decrypt_with(filename, key, &FileOptions::default()).map(|_| ())
+}
+
+/// Function to decrypt a previously encrypted file,
+/// choosing which of the stored metadata is restored.
+/// If the name of the original file is restored, the decrypted file
+/// is created under that name next to the encrypted file,
+/// otherwise under the filename without the .cryptile extension
+/// Returns the name of the decrypted file
+/// 
+/// # Errors
+/// This function gives the same errors as `decrypt`.
+/// 
+/// If the restored name differs from the filename without the
+/// .cryptile extension and a file with that name already exists,
+/// It will give a `std::io::ErrorKind::AlreadyExists` error.
+pub fn decrypt_with(filename: &str, key: &[u8; 32], options: &FileOptions) -> Result<String, Error> {
+    let default_name = filename.strip_suffix(FILE_EXTENSION).unwrap_or(filename);
+    decrypt_to_output(filename, default_name, key, options)
+}
+
+/// Decrypts a file to `default_name`, or when restoring the name,
+/// to the stored name in the directory of `default_name`.
+/// Returns the name of the decrypted file
+fn decrypt_to_output(filename: &str, default_name: &str, key: &[u8; 32],
+    options: &FileOptions) -> Result<String, Error>
+{
+    let mut created = None;
+
+    let open = |metadata: Option<&Metadata>| {
+        let stored_name = metadata.filter(|_| options.metadata).and_then(|m| m.safe_name());
+        let default_path = Path::new(default_name);
+        let file = match stored_name {
+            Some(name) if default_path.file_name().and_then(|n| n.to_str()) != Some(name) => {
+                let path = default_path.with_file_name(name);
+                let output = path_str(&path)?.to_owned();
+                let file = File::options().write(true).create_new(true).open(&output)?;
+                created = Some(output);
+                file
+            }
+            _ => {
+                let file = File::create(default_name)?;
+                created = Some(default_name.to_owned());
+                file
+            }
+        };
+        Ok(file)
+    };
+
+    let result = decrypt_file(filename, key, open);
+    let output = match created {
+        Some(output) => output,
+        None => return result.map(|_| default_name.to_owned()),
+    };
+    let metadata = match result {
+        Ok((metadata, _)) => metadata,
+        Err(e) => {
+            delete(&output);
+            return Err(e)
+        }
+    };
+
+    if let Some(metadata) = metadata.filter(|_| options.metadata) {
+        metadata.apply(&output, options.xattrs)?;
     }
+    Ok(output)
+}
 
+/// Function to decrypt a previously encrypted file,
+/// writing the original content to `output` instead of
+/// the filename without the .cryptile extension
+/// Returns Result type
+/// 
+/// # Errors
+/// This function gives the same errors as `decrypt`
+pub fn decrypt_to_path(filename: &str, output: &str, key: &[u8; 32]) -> Result<(), Error> {
+    decrypt_to_path_with(filename, output, key, &FileOptions::default())
+}
+
+/// Function to decrypt a previously encrypted file to `output`,
+/// choosing which of the stored metadata (other than the name) is restored
+/// Returns Result type
+/// 
+/// # Errors
+/// This function gives the same errors as `decrypt`
+pub fn decrypt_to_path_with(filename: &str, output: &str, key: &[u8; 32],
+    options: &FileOptions) -> Result<(), Error>
+{
+    let (metadata, writer) = decrypt_file(filename, key, |_| File::create(output))?;
+    drop(writer);
+
+    if let Some(metadata) = metadata.filter(|_| options.metadata) {
+        metadata.apply(output, options.xattrs)?;
+    }
+    Ok(())
+}
+
+/// Function to decrypt a previously encrypted file,
+/// writing the original content to `writer` instead of a new file.
+/// The stored metadata isn't written
+/// Returns Result type
+/// 
+/// # Errors
+/// This function gives the same errors as `decrypt`
+pub fn decrypt_to<W: Write>(filename: &str, key: &[u8; 32], writer: &mut W) -> Result<(), Error> {
+    decrypt_file(filename, key, |_| Ok(writer)).map(|_| ())
+}
+
+/// Decrypts a file into the writer returned by `open`, which is given
+/// the stored metadata (if any) once it has been decrypted.
+/// Returns the metadata along with the writer
+fn decrypt_file<W, O>(filename: &str, key: &[u8; 32], open: O) -> Result<(Option<Metadata>, W), Error>
+    where W: Write,
+          O: FnOnce(Option<&Metadata>) -> Result<W, Error>
+{
+    let (mut reader, header, cipher, size) = open_encrypted(filename, key)?;
+
+    let mut splitter = Splitter::new(header.has(FLAG_METADATA), open);
+    decrypt_body(&mut reader, &header, key, &cipher, Some(size), &mut splitter)?;
+    splitter.finish()
+}
+
+/// Function to read the metadata stored in an encrypted file.
+/// Only the chunks holding the metadata (usually the first one) are decrypted
+/// Returns `None` if no metadata is stored
+/// 
+/// # Errors
+/// This function gives the same errors as `decrypt`
+pub fn read_metadata(filename: &str, key: &[u8; 32]) -> Result<Option<Metadata>, Error> {
+    let (mut reader, header, cipher, _) = open_encrypted(filename, key)?;
+    if !header.has(FLAG_METADATA) {
+        return Ok(None)
+    }
+    // Metadata is only written along with authentication
+    if !header.has(FLAG_AUTHENTICATED) {
+        return Err(Error::from(ErrorKind::InvalidData))
+    }
+
+    // The record is at the start of the payload, before any padding
+    let auth = ChunkAuth::new(key, &header);
+    let mut payload = PayloadReader {
+        chunks: ChunkReader::new(&mut reader, &cipher, &auth, header.chunk_size as usize),
+        chunk: Vec::new(),
+        position: 0,
+    };
+    let metadata = match header.compression_level()? {
+        Some(_) => metadata::read_record(&mut zstd::stream::read::Decoder::new(payload)?)?,
+        None => metadata::read_record(&mut payload)?,
+    };
+    Ok(Some(metadata))
+}
+
+/// Function to decrypt an encrypted file read from `reader`,
+/// writing the original content to `writer`
+/// Returns Result type
+/// 
+/// # Errors
+/// This function gives the same errors as `decrypt`,
+/// except that the input isn't required to end with .cryptile
+pub fn decrypt_stream<R: Read, W: Write>(reader: &mut R, writer: &mut W, key: &[u8; 32]) -> Result<(), Error> {
+    let (header, cipher, mut reader) = open_stream(reader, key)?;
+
+    let mut splitter = Splitter::new(header.has(FLAG_METADATA), |_| Ok(writer));
+    decrypt_body(&mut reader, &header, key, &cipher, None, &mut splitter)?;
+    splitter.finish().map(|_| ())
+}
+
+/// Input following the header: the bytes consumed while looking for
+/// a header that turned out to be missing, then the rest of the input
+type Body<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
+/// Reads the header of an encrypted input and checks the key against it
+fn open_stream<R: Read>(mut reader: R, key: &[u8; 32]) -> Result<(Header, Aes256, Body<R>), Error> {
     let key_hash = Hash::hash(key);
     let cipher = cipher_init(key);
 
-    let new_file_name = filename.replace(FILE_EXTENSION, "");
-
-    let mut reader = File::open(filename)?;
-    let size = reader.metadata()?.len();
+    let (header, consumed) = Header::read(&mut reader)?;
+    let mut reader = io::Cursor::new(consumed).chain(reader);
 
     let hash = hash_read_decrypt(&cipher, &mut reader)?;
     if key_hash != hash {
         return Err(Error::from(ErrorKind::InvalidInput))
     }
-    let mut writer = File::create(&new_file_name)?;
 
-    if size < SMALL_FILE_SIZE_LIMIT {
-        decrypt_small_file(&mut reader, &cipher, &mut writer)?;
+    Ok((header, cipher, reader))
+}
+
+/// Opens an encrypted file, reads its header and checks the key against it.
+/// Also returns the size of the file excluding the header
+fn open_encrypted(filename: &str, key: &[u8; 32]) -> Result<(Body<File>, Header, Aes256, u64), Error> {
+    if !filename.ends_with(FILE_EXTENSION) {
+        return Err(Error::from(ErrorKind::Unsupported))
     }
-    else {
-        decrypt_large_file(&mut reader, &cipher, &mut writer)?;
+
+    let reader = File::open(filename)?;
+    let size = reader.metadata()?.len();
+    let (header, cipher, reader) = open_stream(reader, key)?;
+    let size = size.saturating_sub(header.size());
+
+    Ok((reader, header, cipher, size))
+}
+
+/// Decrypts everything following the key slots.
+/// `size` is the size of the encrypted input, if known
+fn decrypt_body<R: Read, W: Write>(reader: &mut R, header: &Header, key: &[u8; 32],
+    cipher: &Aes256, size: Option<u64>, writer: &mut W) -> Result<(), Error>
+{
+    if size.unwrap_or(0) >= SMALL_FILE_SIZE_LIMIT {
+        eprintln!("Decrypting large file in parts...");
     }
 
+    if header.has(FLAG_AUTHENTICATED) {
+        let auth = ChunkAuth::new(key, header);
+        let chunk_size = header.chunk_size as usize;
+        let padded = header.padding()?.is_some();
+
+        // Undone in the reverse order of encryption: padding, then compression
+        if header.compression_level()?.is_some() {
+            let mut decoder = zstd::stream::write::Decoder::new(writer)?;
+            decrypt_padded(reader, cipher, &auth, chunk_size, padded, &mut decoder)?;
+            return decoder.flush()
+        }
+        return decrypt_padded(reader, cipher, &auth, chunk_size, padded, writer)
+    }
+
+    match size {
+        Some(size) if size < SMALL_FILE_SIZE_LIMIT => decrypt_small_file(reader, cipher, writer),
+        _ => decrypt_large_file(reader, cipher, writer),
+    }
+}
+
+fn decrypt_padded<R: Read, W: Write>(reader: &mut R, cipher: &Aes256, auth: &ChunkAuth,
+    chunk_size: usize, padded: bool, writer: &mut W) -> Result<(), Error>
+{
+    if !padded {
+        return decrypt_chunks(reader, cipher, auth, chunk_size, writer)
+    }
+    let mut unpadder = Unpadder::new(writer);
+    decrypt_chunks(reader, cipher, auth, chunk_size, &mut unpadder)?;
+    unpadder.finish()
+}
+
+/// Function to verify the integrity of an encrypted file
+/// by running the whole decryption pipeline and discarding the output
+/// Returns Result type
+/// 
+/// Files encrypted with format version 2 or later are authenticated chunk by chunk.
+/// Older files carry no authentication data, so only the key
+/// and the structure of the file can be checked.
+/// 
+/// # Errors
+/// This function gives the same errors as `decrypt`.
+/// 
+/// If a chunk of the file fails authentication,
+/// It will give a `std::io::ErrorKind::InvalidData` error
+/// mentioning the index of the corrupted chunk.
+pub fn verify(filename: &str, key: &[u8; 32]) -> Result<(), Error> {
+    let (mut reader, header, cipher, size) = open_encrypted(filename, key)?;
+
+    decrypt_body(&mut reader, &header, key, &cipher, Some(size), &mut io::sink())
+}
+
+/// Function to verify every encrypted file (ending with .cryptile)
+/// inside a directory and its subdirectories
+/// Returns a `Report` with the result of `verify` for each file found
+/// 
+/// # Errors
+/// This function will return an appropriate variant of
+/// `std::io::Error` if the directory can't be read
+pub fn verify_dir(dirname: &str, key: &[u8; 32]) -> Result<Report, Error> {
+    let mut entries = Vec::new();
+    walk_tree(Path::new(dirname), LinkPolicy::Skip, None, &mut Vec::new(), &mut entries)?;
+
+    let mut report = Report::default();
+    for entry in entries {
+        match entry {
+            Entry::File(path) => {
+                let filename = match path_str(&path) {
+                    Ok(f) if f.ends_with(FILE_EXTENSION) => f,
+                    _ => continue,
+                };
+                report.add(filename, verify(filename, key));
+            }
+            Entry::Unreadable(path, e) => report.add(&path.to_string_lossy(), Err(e)),
+            _ => (),
+        }
+    }
+    Ok(report)
+}
+
+/// What to do with symbolic links found inside a directory
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LinkPolicy {
+    /// Leave them alone, reporting them as skipped
+    Skip,
+    /// Process the files and directories they point to
+    Follow,
+    /// Report them as failures
+    Fail,
+}
+
+/// What to do with special files (fifos, sockets, devices) found inside a directory
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SpecialPolicy {
+    /// Leave them alone, reporting them as skipped
+    Skip,
+    /// Report them as failures
+    Fail,
+}
+
+/// Options for encrypting or decrypting a single file
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FileOptions {
+    /// Store (or restore) the name, permissions and times of the file
+    /// (see the `metadata` module)
+    pub metadata: bool,
+    /// Also store (or restore) the extended attributes of the file.
+    /// Only supported on unix
+    pub xattrs: bool,
+    /// Write the encrypted file under a random name, so that the
+    /// original name is only known from the stored metadata.
+    /// Requires `metadata`
+    pub obfuscate: bool,
+    /// Compress the content with zstd at the given level before encrypting it.
+    /// Data which doesn't compress well is left uncompressed
+    pub compression: Option<i32>,
+    /// Pad the content before encrypting it, so that the size
+    /// of the encrypted file doesn't tell the size of the content
+    pub padding: Option<Padding>,
+    /// Record in the header that the key was derived with a keyfile,
+    /// so that decrypting can tell that one is needed
+    pub keyfile: bool,
+    /// Record the fingerprint of the key in the header, so that the key
+    /// can be found among several without trying each one.
+    /// Files encrypted with the same key can then be told apart
+    pub fingerprint: bool,
+}
+
+impl Default for FileOptions {
+    fn default() -> Self {
+        FileOptions {
+            metadata: true,
+            xattrs: false,
+            obfuscate: false,
+            compression: None,
+            padding: None,
+            keyfile: false,
+            fingerprint: false,
+        }
+    }
+}
+
+/// Options for encrypting or decrypting a whole directory tree
+pub struct DirOptions<'a> {
+    /// Directory mirroring the input tree, where the output files are written.
+    /// If `None`, output files are written next to the original files
+    pub output: Option<&'a str>,
+    /// Remove the original files once they are processed successfully
+    pub replace: bool,
+    pub links: LinkPolicy,
+    pub special: SpecialPolicy,
+    /// How each file is encrypted or decrypted
+    pub file: FileOptions,
+}
+
+impl Default for DirOptions<'_> {
+    fn default() -> Self {
+        DirOptions {
+            output: None,
+            replace: false,
+            links: LinkPolicy::Skip,
+            special: SpecialPolicy::Skip,
+            file: FileOptions::default(),
+        }
+    }
+}
+
+/// Summary of an operation over several files
+#[derive(Default, Debug)]
+pub struct Report {
+    /// Files processed successfully
+    pub succeeded: Vec<String>,
+    /// Files left alone, along with the reason
+    pub skipped: Vec<(String, &'static str)>,
+    /// Files which couldn't be processed, along with the error
+    pub failed: Vec<(String, Error)>,
+}
+
+impl Report {
+    fn add(&mut self, filename: &str, result: Result<(), Error>) {
+        match result {
+            Ok(()) => self.succeeded.push(filename.to_owned()),
+            Err(e) => self.failed.push((filename.to_owned(), e)),
+        }
+    }
+}
+
+enum Entry {
+    File(PathBuf),
+    Link(PathBuf),
+    Special(PathBuf),
+    Unreadable(PathBuf, Error),
+}
+
+/// Collects the entries of a directory tree, sorted by name.
+/// Directories which can't be read (other than the root) are collected as
+/// unreadable entries instead of stopping the walk.
+/// `exclude` is a canonical path of a directory not to enter, and
+/// `visited` keeps track of directories already walked when following links
+fn walk_tree(dir: &Path, links: LinkPolicy, exclude: Option<&Path>,
+    visited: &mut Vec<PathBuf>, entries: &mut Vec<Entry>) -> Result<(), Error>
+{
+    let mut dir_entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, Error>>()?;
+    dir_entries.sort_by_key(|e| e.file_name());
+
+    for entry in dir_entries {
+        let path = entry.path();
+        let mut file_type = entry.file_type()?;
+
+        if file_type.is_symlink() {
+            if links != LinkPolicy::Follow {
+                entries.push(Entry::Link(path));
+                continue
+            }
+            file_type = match fs::metadata(&path) {
+                Ok(m) => m.file_type(),
+                Err(e) => {
+                    entries.push(Entry::Unreadable(path, e));
+                    continue
+                }
+            };
+        }
+
+        if file_type.is_dir() {
+            let canonical = fs::canonicalize(&path)?;
+            if Some(canonical.as_path()) == exclude || visited.contains(&canonical) {
+                continue
+            }
+            visited.push(canonical);
+            if let Err(e) = walk_tree(&path, links, exclude, visited, entries) {
+                entries.push(Entry::Unreadable(path, e));
+            }
+        }
+        else if file_type.is_file() {
+            entries.push(Entry::File(path));
+        }
+        else {
+            entries.push(Entry::Special(path));
+        }
+    }
     Ok(())
 }
 
+fn path_str(path: &Path) -> Result<&str, Error> {
+    path.to_str().ok_or_else(|| Error::new(ErrorKind::InvalidData, "path is not valid UTF-8"))
+}
+
+/// Function to encrypt every file inside a directory and its subdirectories
+/// using a 32-bit key. Files already ending with .cryptile are skipped,
+/// and files whose encrypted file already exists fail rather than overwrite it.
+/// Errors with individual files don't stop the operation,
+/// they are collected in the returned `Report` instead
+/// 
+/// # Errors
+/// This function will return an appropriate variant of
+/// `std::io::Error` if the directory can't be read
+/// or the output directory can't be created
+pub fn encrypt_dir(dirname: &str, key: &[u8; 32], options: &DirOptions) -> Result<Report, Error> {
+    let plan = |path: &str, mirrored: Option<&str>| {
+        if path.ends_with(FILE_EXTENSION) {
+            return Err("already encrypted")
+        }
+        Ok(mirrored.unwrap_or(path).to_owned() + FILE_EXTENSION)
+    };
+    process_dir(dirname, options, plan, |path, output| {
+        // e.g. both `x` and `x.cryptile` are in the tree
+        if !options.file.obfuscate && Path::new(output).exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists, "the encrypted file already exists"))
+        }
+        encrypt_to_output(path, output, key, &options.file)
+    })
+}
+
+/// Function to decrypt every encrypted file (ending with .cryptile) inside
+/// a directory and its subdirectories. Other files are skipped.
+/// Errors with individual files (such as a wrong key) don't stop the operation,
+/// they are collected in the returned `Report` instead
+/// 
+/// # Errors
+/// This function will return an appropriate variant of
+/// `std::io::Error` if the directory can't be read
+/// or the output directory can't be created
+pub fn decrypt_dir(dirname: &str, key: &[u8; 32], options: &DirOptions) -> Result<Report, Error> {
+    let plan = |path: &str, mirrored: Option<&str>| {
+        match mirrored.unwrap_or(path).strip_suffix(FILE_EXTENSION) {
+            Some(output) => Ok(output.to_owned()),
+            None => Err("not encrypted"),
+        }
+    };
+    process_dir(dirname, options, plan, |path, output| {
+        decrypt_to_output(path, output, key, &options.file)
+    })
+}
+
+/// Walks a directory tree and processes each file found.
+/// `plan` is called with the path of the file and its path inside the output
+/// directory (if any), and gives the path of the file to create, or the
+/// reason to skip the file. `run` then creates it from the original file,
+/// and gives the path it was actually created at (which can be another
+/// name in the same directory)
+fn process_dir<P, R>(dirname: &str, options: &DirOptions, plan: P, run: R) -> Result<Report, Error>
+    where P: Fn(&str, Option<&str>) -> Result<String, &'static str>,
+          R: Fn(&str, &str) -> Result<String, Error>
+{
+    let root = Path::new(dirname);
+    if !fs::metadata(root)?.is_dir() {
+        return Err(Error::new(ErrorKind::InvalidInput, "not a directory"))
+    }
+
+    let exclude = match options.output {
+        Some(output) => {
+            fs::create_dir_all(output)?;
+            Some(fs::canonicalize(output)?)
+        }
+        None => None,
+    };
+
+    let mut entries = Vec::new();
+    let mut visited = vec![fs::canonicalize(root)?];
+    walk_tree(root, options.links, exclude.as_deref(), &mut visited, &mut entries)?;
+
+    let mut report = Report::default();
+    for entry in entries {
+        let path = match entry {
+            Entry::File(path) => path,
+            Entry::Link(path) => {
+                let name = path.to_string_lossy().into_owned();
+                match options.links {
+                    LinkPolicy::Fail => report.failed.push((name, Error::other("symbolic link"))),
+                    _ => report.skipped.push((name, "symbolic link")),
+                }
+                continue
+            }
+            Entry::Special(path) => {
+                let name = path.to_string_lossy().into_owned();
+                match options.special {
+                    SpecialPolicy::Fail => report.failed.push((name, Error::other("special file"))),
+                    SpecialPolicy::Skip => report.skipped.push((name, "special file")),
+                }
+                continue
+            }
+            Entry::Unreadable(path, e) => {
+                report.failed.push((path.to_string_lossy().into_owned(), e));
+                continue
+            }
+        };
+
+        let filename = match path_str(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                report.failed.push((path.to_string_lossy().into_owned(), e));
+                continue
+            }
+        };
+
+        let mirrored = match options.output {
+            Some(output) => {
+                let relative = path.strip_prefix(root).unwrap_or(&path);
+                let mirrored = Path::new(output).join(relative);
+                if let Some(parent) = mirrored.parent() {
+                    if let Err(e) = fs::create_dir_all(parent) {
+                        report.failed.push((filename.to_owned(), e));
+                        continue
+                    }
+                }
+                match path_str(&mirrored) {
+                    Ok(m) => Some(m.to_owned()),
+                    Err(e) => {
+                        report.failed.push((filename.to_owned(), e));
+                        continue
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let output = match plan(filename, mirrored.as_deref()) {
+            Ok(output) => output,
+            Err(reason) => {
+                report.skipped.push((filename.to_owned(), reason));
+                continue
+            }
+        };
+
+        let existed = Path::new(&output).exists();
+        match run(filename, &output) {
+            Ok(created) => {
+                if options.replace {
+                    delete(filename);
+                }
+                report.succeeded.push(created);
+            }
+            Err(e) => {
+                if !existed {
+                    delete(&output);
+                }
+                report.failed.push((filename.to_owned(), e));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
 // pub fn encrypt_parallel(filename: &str, key: &str) -> Result<(), Error> {
 //     Ok(())
 // }
@@ -404,6 +1609,13 @@ pub fn decrypt(filename: &str, key: &[u8; 32]) -> Result<(), Error> {
 // }
 
 
+/// Function to compute the fingerprint of a key: a short
+/// value telling keys apart which reveals nothing about the key
+pub fn fingerprint(key: &[u8; 32]) -> [u8; FINGERPRINT_SIZE] {
+    let mac = HMAC::mac(b"cryptile key fingerprint", key);
+    mac[..FINGERPRINT_SIZE].try_into().unwrap()
+}
+
 /// Function to determine whether a key is correct for an encrypted file
 /// Returns a `Result<bool>` type
 /// 
@@ -419,16 +1631,54 @@ pub fn is_correct_key(filename: &str, key: &[u8; 32]) -> Result<bool, Error> {
         return Err(Error::from(ErrorKind::Unsupported))
     }
 
-    let key_hash = Hash::hash(key);
-    let cipher = cipher_init(key);
+    match open_stream(File::open(filename)?, key) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::InvalidInput => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Function to inspect the header of an encrypted file without needing the key
+/// Returns a `Result<FileInfo>` type
+/// 
+/// # Errors
+/// This function will return an appropriate variant of
+/// `std::io::Error` if there is any error reading the file
+/// 
+/// If the file doesn't have a cryptile header and doesn't end with .cryptile,
+/// or was written by a newer version of this tool,
+/// It will give a `std::io::ErrorKind::Unsupported` error.
+pub fn inspect(filename: &str) -> Result<FileInfo, Error> {
     let mut reader = File::open(filename)?;
+    let file_size = reader.metadata()?.len();
+    let (header, _) = Header::read(&mut reader)?;
 
-    let hash = hash_read_decrypt(&cipher, &mut reader)?;
-    if key_hash != hash {
-        return Ok(false)
+    if header.version == 0 && !filename.ends_with(FILE_EXTENSION) {
+        return Err(Error::from(ErrorKind::Unsupported))
     }
 
-    Ok(true)
+    let recorded = header.version > 0;
+    Ok(FileInfo {
+        version: header.version,
+        cipher: "AES-256",
+        kdf: "SHA-256 (no parameters)",
+        key_slots: header.key_slots,
+        authenticated: header.has(FLAG_AUTHENTICATED),
+        archive: header.has(FLAG_ARCHIVE),
+        metadata: header.has(FLAG_METADATA),
+        compression_level: header.compression_level()?,
+        padding: header.padding()?,
+        keyfile: header.has(FLAG_KEYFILE),
+        fingerprint: header.fingerprint()?,
+        chunk_size: if recorded { Some(header.chunk_size) } else { None },
+        plaintext_size: if recorded && header.plaintext_size != UNKNOWN_SIZE {
+            Some(header.plaintext_size)
+        } else {
+            None
+        },
+        created: if recorded { Some(header.created) } else { None },
+        file_size,
+    })
 }
 
 /// Function to try to delete a file from filesystem
@@ -488,6 +1738,321 @@ mod tests {
         decrypt("test.jpg.cryptile", &key).expect("Error in Decrypting");
     }
 
+    fn temp_file(name: &str, contents: &[u8]) -> String {
+        let mut path = std::env::temp_dir();
+        path.push(format!("cryptile-{}-{}", std::process::id(), name));
+        let path = path.to_str().unwrap().to_owned();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn inspect_header() {
+        let key = Hash::hash(TEST_KEY.as_bytes());
+        let filename = temp_file("inspect.txt", b"some secret contents");
+        encrypt(&filename, &key).expect("Error in Encrypting");
+
+        let encrypted = filename.clone() + FILE_EXTENSION;
+        let info = inspect(&encrypted).expect("Error in Inspecting");
+        assert_eq!(info.version, FORMAT_VERSION);
+        assert_eq!(info.key_slots, 1);
+        assert_eq!(info.plaintext_size, Some(20));
+        assert!(info.created.is_some());
+
+        delete(&filename);
+        decrypt(&encrypted, &key).expect("Error in Decrypting");
+        assert_eq!(fs::read(&filename).unwrap(), b"some secret contents");
+
+        delete(&filename);
+        delete(&encrypted);
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let key = Hash::hash(TEST_KEY.as_bytes());
+        let filename = temp_file("metadata.txt", b"file with metadata");
+        let modified = UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789);
+        let times = fs::FileTimes::new().set_modified(modified).set_accessed(modified);
+        File::options().write(true).open(&filename).unwrap().set_times(times).unwrap();
+        metadata::set_mode(Path::new(&filename), 0o640).unwrap();
+
+        let encrypted = encrypt_with(&filename, &key, &FileOptions::default()).unwrap();
+        assert!(inspect(&encrypted).unwrap().metadata);
+        let stored = read_metadata(&encrypted, &key).unwrap().unwrap();
+        assert_eq!(stored.accessed, modified);
+        assert_eq!(stored.modified, modified);
+        assert_eq!(stored.mode, 0o640);
+        delete(&filename);
+
+        // The original name is restored even if the encrypted file was renamed
+        let renamed = temp_file("renamed.cryptile", b"");
+        fs::rename(&encrypted, &renamed).unwrap();
+        let output = decrypt_with(&renamed, &key, &FileOptions::default()).unwrap();
+        assert_eq!(output, filename);
+        let restored = fs::metadata(&filename).unwrap();
+        assert_eq!(restored.modified().unwrap(), modified);
+        assert_eq!(restored.accessed().unwrap(), modified);
+        assert_eq!(metadata::mode_of(&restored), 0o640);
+        assert_eq!(fs::read(&filename).unwrap(), b"file with metadata");
+        delete(&filename);
+
+        let options = FileOptions { metadata: false, ..FileOptions::default() };
+        let output = decrypt_with(&renamed, &key, &options).unwrap();
+        assert_eq!(output, renamed.strip_suffix(FILE_EXTENSION).unwrap());
+        assert_eq!(fs::read(&output).unwrap(), b"file with metadata");
+
+        let mut content = Vec::new();
+        decrypt_to(&renamed, &key, &mut content).unwrap();
+        assert_eq!(content, b"file with metadata");
+
+        delete(&output);
+        delete(&renamed);
+    }
+
+    #[test]
+    fn metadata_read_from_start() {
+        let key = Hash::hash(TEST_KEY.as_bytes());
+        let filename = temp_file("packed.log", &[b'x'; 5000]);
+        let options = FileOptions {
+            compression: Some(DEFAULT_COMPRESSION_LEVEL),
+            padding: Some(Padding::PowerOfTwo),
+            ..FileOptions::default()
+        };
+        let encrypted = encrypt_with(&filename, &key, &options).unwrap();
+        delete(&filename);
+        assert!(read_metadata(&encrypted, &key).unwrap().unwrap().name.ends_with("packed.log"));
+
+        let wrong_key = Hash::hash(b"wrong key");
+        assert_eq!(read_metadata(&encrypted, &wrong_key).err().unwrap().kind(), ErrorKind::InvalidInput);
+
+        let mut bytes = fs::read(&encrypted).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&encrypted, &bytes).unwrap();
+        assert_eq!(read_metadata(&encrypted, &key).err().unwrap().to_string(), "chunk 0 is corrupted");
+
+        delete(&encrypted);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_mode_bits_not_restored() {
+        use std::os::unix::fs::PermissionsExt;
+        let filename = temp_file("setuid.sh", b"#!/bin/sh");
+        let mut stored = metadata::Metadata::read(&filename, false).unwrap();
+        // As a crafted file could hold
+        stored.mode = 0o6755;
+        stored.apply(&filename, false).unwrap();
+        assert_eq!(fs::metadata(&filename).unwrap().permissions().mode() & 0o7777, 0o755);
+
+        metadata::set_mode(Path::new(&filename), 0o4755).unwrap();
+        assert_eq!(metadata::Metadata::read(&filename, false).unwrap().mode, 0o755);
+        delete(&filename);
+    }
+
+    #[test]
+    fn obfuscated_names() {
+        let key = Hash::hash(TEST_KEY.as_bytes());
+        let filename = temp_file("obfuscated.txt", b"hidden name");
+        let options = FileOptions { obfuscate: true, ..FileOptions::default() };
+
+        let encrypted = encrypt_with(&filename, &key, &options).unwrap();
+        let name = Path::new(&encrypted).file_name().unwrap().to_str().unwrap();
+        assert!(!name.contains("obfuscated"));
+        assert_eq!(Path::new(&encrypted).parent(), Path::new(&filename).parent());
+        assert!(read_metadata(&encrypted, &key).unwrap().unwrap().name.ends_with("obfuscated.txt"));
+        delete(&filename);
+
+        assert_eq!(decrypt_with(&encrypted, &key, &FileOptions::default()).unwrap(), filename);
+        assert_eq!(fs::read(&filename).unwrap(), b"hidden name");
+
+        let options = FileOptions { metadata: false, obfuscate: true, ..FileOptions::default() };
+        assert_eq!(encrypt_with(&filename, &key, &options).err().unwrap().kind(), ErrorKind::InvalidInput);
+
+        delete(&filename);
+        delete(&encrypted);
+    }
+
+    #[test]
+    fn compression_round_trip() {
+        let key = Hash::hash(TEST_KEY.as_bytes());
+        let options = FileOptions { compression: Some(DEFAULT_COMPRESSION_LEVEL), ..FileOptions::default() };
+
+        let text: Vec<u8> = (0..200_000).flat_map(|i| format!("log line {}\n", i).into_bytes()).collect();
+        let filename = temp_file("compressed.log", &text);
+        let encrypted = encrypt_with(&filename, &key, &options).unwrap();
+        let info = inspect(&encrypted).unwrap();
+        assert_eq!(info.compression_level, Some(DEFAULT_COMPRESSION_LEVEL));
+        assert!(info.file_size < text.len() as u64 / 4);
+
+        let mut content = Vec::new();
+        decrypt_to(&encrypted, &key, &mut content).unwrap();
+        assert_eq!(content, text);
+        delete(&filename);
+        delete(&encrypted);
+
+        // Data which doesn't compress is stored as is
+        let mut random = vec![0u8; 100_000];
+        getrandom::fill(&mut random).unwrap();
+        let mut encrypted = Vec::new();
+        encrypt_stream_with(&mut random.as_slice(), &mut encrypted, &key, &options).unwrap();
+        let mut content = Vec::new();
+        decrypt_stream(&mut encrypted.as_slice(), &mut content, &key).unwrap();
+        assert_eq!(content, random);
+        let (header, _) = Header::read(&mut encrypted.as_slice()).unwrap();
+        assert!(!header.has(FLAG_COMPRESSED));
+    }
+
+    #[test]
+    fn fingerprint_recorded() {
+        let key = Hash::hash(TEST_KEY.as_bytes());
+        let filename = temp_file("fingerprint.txt", b"content");
+        let plain = encrypt_with(&filename, &key, &FileOptions::default()).unwrap();
+        assert_eq!(inspect(&plain).unwrap().fingerprint, None);
+        delete(&plain);
+
+        let options = FileOptions { fingerprint: true, ..FileOptions::default() };
+        let encrypted = encrypt_with(&filename, &key, &options).unwrap();
+        assert_eq!(inspect(&encrypted).unwrap().fingerprint, Some(fingerprint(&key)));
+        assert_ne!(fingerprint(&key), fingerprint(&Hash::hash(b"other key")));
+        verify(&encrypted, &key).unwrap();
+        delete(&filename);
+        delete(&encrypted);
+    }
+
+    #[test]
+    fn verify_detects_corruption() {
+        let key = Hash::hash(TEST_KEY.as_bytes());
+        let filename = temp_file("verify.txt", &[7u8; 1000]);
+        encrypt(&filename, &key).expect("Error in Encrypting");
+        delete(&filename);
+
+        let encrypted = filename.clone() + FILE_EXTENSION;
+        verify(&encrypted, &key).expect("Error in Verifying");
+
+        let wrong_key = Hash::hash(b"wrong key");
+        assert_eq!(verify(&encrypted, &wrong_key).err().unwrap().kind(), ErrorKind::InvalidInput);
+
+        let mut bytes = fs::read(&encrypted).unwrap();
+        bytes[HEADER_SIZE + 32 + 100] ^= 1;
+        fs::write(&encrypted, &bytes).unwrap();
+        let err = verify(&encrypted, &key).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "chunk 0 is corrupted");
+
+        bytes[HEADER_SIZE + 32 + 100] ^= 1;
+        bytes.truncate(bytes.len() - 1);
+        fs::write(&encrypted, &bytes).unwrap();
+        assert_eq!(verify(&encrypted, &key).err().unwrap().kind(), ErrorKind::InvalidData);
+
+        delete(&encrypted);
+    }
+
+    /// Reader returning at most a few bytes per call, like a pipe
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            let n = buf.len().min(self.0.len()).min(5);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn stream_round_trip_with_short_reads() {
+        let key = Hash::hash(TEST_KEY.as_bytes());
+        let plaintext: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+
+        let mut encrypted = Vec::new();
+        encrypt_stream(&mut Trickle(&plaintext), &mut encrypted, &key).expect("Error in Encrypting");
+
+        let mut decrypted = Vec::new();
+        decrypt_stream(&mut Trickle(&encrypted), &mut decrypted, &key).expect("Error in Decrypting");
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn encrypt_dir_into_mirror() {
+        let key = Hash::hash(TEST_KEY.as_bytes());
+        let root = temp_file("tree", b"");
+        delete(&root);
+        fs::create_dir_all(root.clone() + "/sub").unwrap();
+        fs::write(root.clone() + "/a.txt", b"first").unwrap();
+        fs::write(root.clone() + "/sub/b.txt", b"second").unwrap();
+        fs::write(root.clone() + "/sub/c.txt.cryptile", b"not really").unwrap();
+
+        let mirror = root.clone() + "/mirror";
+        let options = DirOptions { output: Some(&mirror), ..DirOptions::default() };
+        let report = encrypt_dir(&root, &key, &options).expect("Error in Encrypting");
+        assert_eq!(report.succeeded.len(), 2);
+        assert_eq!(report.skipped.len(), 1);
+        assert!(report.failed.is_empty());
+        assert!(Path::new(&(mirror.clone() + "/sub/b.txt.cryptile")).is_file());
+
+        let restored = root.clone() + "/restored";
+        let options = DirOptions { output: Some(&restored), ..DirOptions::default() };
+        let report = decrypt_dir(&mirror, &key, &options).expect("Error in Decrypting");
+        assert_eq!(report.succeeded.len(), 2);
+        assert_eq!(fs::read(restored + "/sub/b.txt").unwrap(), b"second");
+
+        let report = decrypt_dir(&mirror, &Hash::hash(b"wrong"), &DirOptions::default()).unwrap();
+        assert_eq!(report.failed.len(), 2);
+        assert!(Path::new(&(mirror + "/a.txt.cryptile")).is_file());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn encrypt_dir_keeps_existing_output() {
+        let key = Hash::hash(TEST_KEY.as_bytes());
+        let root = temp_file("collision", b"");
+        delete(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.clone() + "/x", b"plain").unwrap();
+        fs::write(root.clone() + "/x.cryptile", b"kept as is").unwrap();
+
+        let report = encrypt_dir(&root, &key, &DirOptions::default()).unwrap();
+        assert!(report.succeeded.is_empty());
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].1.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(root.clone() + "/x.cryptile").unwrap(), b"kept as is");
+
+        let options = DirOptions { replace: true, ..DirOptions::default() };
+        encrypt_dir(&root, &key, &options).unwrap();
+        assert_eq!(fs::read(root.clone() + "/x").unwrap(), b"plain");
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn huge_chunk_size_rejected() {
+        let key = Hash::hash(TEST_KEY.as_bytes());
+        let filename = temp_file("chunk-size.txt", b"small file");
+        let encrypted = encrypt_with(&filename, &key, &FileOptions::default()).unwrap();
+        delete(&filename);
+
+        let mut bytes = fs::read(&encrypted).unwrap();
+        bytes[12..16].copy_from_slice(&(u32::MAX - 15).to_le_bytes());
+        fs::write(&encrypted, &bytes).unwrap();
+        assert_eq!(inspect(&encrypted).err().unwrap().kind(), ErrorKind::InvalidData);
+        assert_eq!(verify(&encrypted, &key).err().unwrap().kind(), ErrorKind::InvalidData);
+        let mut content = Vec::new();
+        assert_eq!(decrypt_to(&encrypted, &key, &mut content).err().unwrap().kind(), ErrorKind::InvalidData);
+
+        delete(&encrypted);
+    }
+
+    #[test]
+    fn inspect_rejects_plain_file() {
+        let filename = temp_file("plain.txt", b"not encrypted");
+        let err = inspect(&filename).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        delete(&filename);
+    }
+
     #[test]
     fn num_cpus() {
         let default_parallelism_approx = available_parallelism().unwrap().get();
@@ -1,60 +1,403 @@
 use cargo_cryptile as cryptile;
+use cryptile::{archive, generate, mnemonic, strength};
 use std::env;
 use std::process;
-use std::io::{self, ErrorKind, Write};
+use std::io::{self, BufWriter, Error, ErrorKind, IsTerminal, Write};
+use std::path::Path;
 use dialoguer::Password;
 
 mod config;
-use config::{Config, Operation, Pass};
+mod edit;
+mod exec;
+mod keystore;
+use config::{ArchiveCommand, Config, ConfigCommand, Generate, KeysCommand, Operation, Pass};
+use keystore::{Bundle, Defaults, Imported, Key};
 
 
 impl<'a> Config<'a> {
     fn get_args(&self) -> ([u8; 32], &str, bool) {
-        let key = match self.get_key() {
-            Ok(k) => k,
+        let filename = self.file().unwrap();
+        let replace = self.replace();
+        let encrypting = matches!(self.operation,
+            Operation::Encrypt | Operation::Archive(ArchiveCommand::Create));
+
+        // Told before asking for a password which wouldn't be enough
+        if !encrypting && self.keyfile().is_none()
+            && matches!(cryptile::inspect(filename), Ok(info) if info.keyfile)
+        {
+            eprintln!("Error: {} was encrypted with a keyfile. Give it with --keyfile <PATH>", filename);
+            process::exit(1)
+        }
+
+        if let Some(Pass::Auto { prompt }) = self.pass {
+            return (self.find_saved_key(filename, prompt), filename, replace);
+        }
+
+        let key = match self.pass {
+            None => None,
+            // Only a new key needs confirming, a mistyped one is caught when decrypting
+            Some(Pass::Prompt) => Some(config::key_of(&get_pass_input(encrypting))),
+            _ => match self.get_key() {
+                Ok(k) => Some(k),
+                Err(m) => {
+                    eprintln!("{}", m);
+                    process::exit(1)
+                }
+            }
+        };
+        if let Some(Pass::Given { given: _ }) = self.pass {
+            eprintln!("Warning: A password given with -p is visible in the shell history and to other users. \
+                    Leave out the password options to be prompted for it");
+        }
+
+        (self.with_keyfile(key), filename, replace)
+    }
+
+    fn with_keyfile(&self, key: Option<[u8; 32]>) -> [u8; 32] {
+        match self.keyfile() {
+            None => key.unwrap(),
+            Some(keyfile) => match cryptile::keyfile::combine(key.as_ref(), keyfile) {
+                Ok(k) => k,
+                Err(e) => {
+                    eprintln!("Error reading the keyfile: {}", verify_error_message(&e));
+                    process::exit(1)
+                }
+            }
+        }
+    }
+
+    /// Tries the master and the saved passwords in turn, telling which one is the key.
+    /// Those not matching a fingerprint recorded in the header aren't tried.
+    /// If `prompt`, the password is asked for when none of them is the key,
+    /// or the master password entered is wrong
+    fn find_saved_key(&self, filename: &str, prompt: bool) -> [u8; 32] {
+        let saved = match keystore::Keystore::open_unless_wrong_master() {
+            Ok(saved) => saved,
             Err(m) => {
                 eprintln!("{}", m);
                 process::exit(1)
             }
         };
-        let filename = self.file().unwrap();
-        let replace = self.replace();
+        let recorded = match cryptile::inspect(filename) {
+            Ok(info) => info.fingerprint,
+            Err(e) => {
+                eprintln!("Error: {}", verify_error_message(&e));
+                process::exit(1)
+            }
+        };
 
-        (key, filename, replace)
+        let saved = match saved {
+            Some(saved) => saved,
+            None if prompt => {
+                eprintln!("Wrong master password, the saved passwords weren't tried");
+                return self.with_keyfile(Some(config::key_of(&get_pass_input(false))))
+            }
+            None => {
+                eprintln!("Wrong master password");
+                process::exit(1)
+            }
+        };
+        let candidates = saved.master().map(|key| ("the master password".to_owned(), key)).into_iter()
+            .chain(saved.keys().iter().map(|k| (format!("the saved password {}", k.identifier), k.key)));
+        for (name, key) in candidates {
+            let key = self.with_keyfile(Some(key));
+            if recorded.is_some_and(|f| f != cryptile::fingerprint(&key)) {
+                continue
+            }
+            match cryptile::is_correct_key(filename, &key) {
+                Ok(true) => {
+                    eprintln!("Using {}", name);
+                    return key
+                }
+                Ok(false) => (),
+                Err(e) => {
+                    eprintln!("Error: {}", verify_error_message(&e));
+                    process::exit(1)
+                }
+            }
+        }
+
+        if !prompt {
+            eprintln!("Error: None of the saved passwords is the key of {}", filename);
+            process::exit(1)
+        }
+        self.with_keyfile(Some(config::key_of(&get_pass_input(false))))
     }
 }
 
-fn get_pass_input() -> String {
-    let password = Password::new().with_prompt("Enter a password")
-        .with_confirmation("Confirm password", "Passwords mismatching")
-        .interact();
+/// Asks for a password, confirming it if it is a new one
+fn get_pass_input(confirm: bool) -> String {
+    // Empty passwords are refused here rather than by the prompt,
+    // which would otherwise ask again forever once the input has ended
+    let mut password = Password::new();
+    password.with_prompt("Enter a password").allow_empty_password(true);
+    if confirm {
+        password.with_confirmation("Confirm password", "Passwords mismatching");
+    }
+    let password = password.interact();
 
     match password {
         Err(_) => {
             eprintln!("Cannot read from the stdin");
             process::exit(1)
         }
+        Ok(password) if password.is_empty() => {
+            eprintln!("Error: No password entered");
+            process::exit(1)
+        }
         Ok(password) => password
     }
 }
 
+/// Tells how long a password being saved would take to crack,
+/// and refuses it if it is too guessable unless `allow_weak`
+fn check_strength(password: &str, allow_weak: bool) {
+    let strength = strength::estimate(password);
+    eprintln!("Estimated time to crack: {}",
+        strength::format_duration(strength.crack_seconds(strength::OFFLINE_FAST_HASH_RATE)));
+    if let Some(warning) = strength.warning {
+        eprintln!("Warning: {}", warning);
+    }
+    if strength.is_weak() && !allow_weak {
+        eprintln!("Error: The password is too weak. Choose a stronger one, or use --allow-weak");
+        process::exit(1)
+    }
+}
+
+/// Filename standing for stdin (input) and stdout (output)
+const STDIO: &str = "-";
+
 fn get_input(s: &str) -> String {
-    print!("{}", s);
-    io::stdout().flush().unwrap();
+    eprint!("{}", s);
+    io::stderr().flush().unwrap();
 
     let mut input = String::new();
 
-    if let Err(_) = io::stdin().read_line(&mut input) {
+    if io::stdin().read_line(&mut input).is_err() {
         eprintln!("Cannot read from the stdin");
         process::exit(1)
     }
     input.trim().to_owned()
 }
 
+fn format_timestamp(secs: u64) -> String {
+    // Converts days since the unix epoch to a civil (year, month, day) date
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let time = secs % 86_400;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
+
+fn print_info(info: &cryptile::FileInfo) {
+    let unknown = "not recorded".to_owned();
+
+    println!("Format version:  {}", info.version);
+    println!("Cipher:          {}", info.cipher);
+    println!("Key derivation:  {}", info.kdf);
+    println!("Key slots:       {}", info.key_slots);
+    println!("Authenticated:   {}", if info.authenticated { "yes" } else { "no" });
+    println!("Archive:         {}", if info.archive { "yes" } else { "no" });
+    println!("Metadata:        {}", if info.metadata { "stored" } else { "not stored" });
+    println!("Compression:     {}",
+        info.compression_level.map_or("none".to_owned(), |l| format!("zstd (level {})", l)));
+    println!("Padding:         {}", match info.padding {
+        Some(cryptile::Padding::PowerOfTwo) => "power of two",
+        Some(cryptile::Padding::Padme) => "Padmé",
+        None => "none",
+    });
+    println!("Keyfile:         {}", if info.keyfile { "required" } else { "not used" });
+    println!("Fingerprint:     {}", info.fingerprint.map_or(unknown.clone(), |f| format_fingerprint(&f)));
+    println!("Chunk size:      {}",
+        info.chunk_size.map_or(unknown.clone(), |s| format!("{} bytes", s)));
+    println!("Plaintext size:  {}",
+        info.plaintext_size.map_or(unknown.clone(), |s| format!("{} bytes", s)));
+    println!("Encrypted size:  {} bytes", info.file_size);
+    println!("Created:         {}",
+        info.created.map_or(unknown, format_timestamp));
+}
+
+/// Number of bytes looked at to decide whether content is binary
+const BINARY_CHECK_SIZE: usize = 8000;
+
+/// Writer holding back the start of the output until it can tell
+/// whether the content is binary, refusing to write binary content
+struct TextGuard<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+    checked: bool,
+    refused: bool,
+}
+
+impl<W: Write> TextGuard<W> {
+    /// Passes everything through unchecked if `enabled` is false
+    fn new(inner: W, enabled: bool) -> TextGuard<W> {
+        TextGuard { inner, buffer: Vec::new(), checked: !enabled, refused: false }
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        self.checked = true;
+        let binary = self.buffer.contains(&0) || match std::str::from_utf8(&self.buffer) {
+            Ok(_) => false,
+            // Only allowed to fail on a character cut off at the end of the buffer
+            Err(e) => e.error_len().is_some(),
+        };
+        if binary {
+            self.refused = true;
+            return Err(Error::new(ErrorKind::InvalidData, "binary content"))
+        }
+        self.inner.write_all(&self.buffer)?;
+        self.buffer = Vec::new();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for TextGuard<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.checked {
+            return self.inner.write(buf)
+        }
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= BINARY_CHECK_SIZE {
+            self.check()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.checked {
+            self.check()?;
+        }
+        self.inner.flush()
+    }
+}
+
+/// Prints the outcome for every file of a report and a summary.
+/// Exits with an error code if any file failed
+fn print_report(report: &cryptile::Report, done: &str) {
+    for file in &report.succeeded {
+        println!("OK       {}", file);
+    }
+    for (file, reason) in &report.skipped {
+        println!("SKIPPED  {}: {}", file, reason);
+    }
+    for (file, e) in &report.failed {
+        println!("FAILED   {}: {}", file, verify_error_message(e));
+    }
+    println!("{} files {}, {} skipped, {} failed",
+        report.succeeded.len(), done, report.skipped.len(), report.failed.len());
+
+    if !report.failed.is_empty() {
+        process::exit(1)
+    }
+}
+
+fn print_member(member: &archive::Member) {
+    let kind = match member.kind {
+        archive::MemberKind::File => '-',
+        archive::MemberKind::Dir => 'd',
+        archive::MemberKind::Symlink => 'l',
+    };
+    let time = if member.mtime < 0 { 0 } else { member.mtime as u64 };
+    println!("{} {:04o} {:>12}  {}  {}", kind, member.mode, member.size,
+        format_timestamp(time), member.path);
+}
+
+/// Gives the name of every encrypted file in a directory
+/// along with the original name of the file
+fn list_names(dirname: &str, key: &[u8; 32]) -> io::Result<Vec<(String, String)>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dirname)? {
+        let entry = entry?;
+        let file = entry.file_name().to_string_lossy().into_owned();
+        if !file.ends_with(cryptile::FILE_EXTENSION) || !entry.file_type()?.is_file() {
+            continue
+        }
+
+        let path = entry.path();
+        let name = match cryptile::read_metadata(&path.to_string_lossy(), key) {
+            Ok(Some(metadata)) => metadata.name,
+            Ok(None) => format!("{} (name not stored)",
+                file.strip_suffix(cryptile::FILE_EXTENSION).unwrap()),
+            Err(e) => format!("({})", verify_error_message(&e)),
+        };
+        names.push((file, name));
+    }
+    Ok(names)
+}
+
+fn print_imported(outcome: Imported) {
+    match outcome {
+        Imported::Added(id) => println!("ADDED        {}", id),
+        Imported::Present(id) => println!("UNCHANGED    {}", id),
+        Imported::Skipped(id) => println!("SKIPPED      {}: identifier taken", id),
+        Imported::Overwritten(id) => println!("OVERWRITTEN  {}", id),
+        Imported::Renamed(id, new_id) => println!("RENAMED      {} to {}", id, new_id),
+        Imported::Master => println!("MASTER       replaced by the master password of the bundle"),
+    }
+}
+
+/// Numbered words, six to a line
+fn print_mnemonic(words: &[&str]) {
+    for (row, line) in words.chunks(6).enumerate() {
+        let line: Vec<String> = line.iter().enumerate()
+            .map(|(i, w)| format!("{:>2}. {:<9}", row * 6 + i + 1, w))
+            .collect();
+        println!("{}", line.join(" ").trim_end());
+    }
+}
+
+/// Groups of four hex digits, e.g. `3f2a:91c0:5b7e:d401`
+fn format_fingerprint(fingerprint: &[u8]) -> String {
+    fingerprint.chunks(2)
+        .map(|c| c.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn verify_error_message(e: &Error) -> String {
+    match e.kind() {
+        ErrorKind::Unsupported => "Unsupported File type".to_owned(),
+        ErrorKind::PermissionDenied => "Permission Denied".to_owned(),
+        ErrorKind::InvalidInput => "Wrong key given".to_owned(),
+        ErrorKind::NotFound => "File Not Found!".to_owned(),
+        ErrorKind::UnexpectedEof => "Unexpected End of File".to_owned(),
+        _ => e.to_string(),
+    }
+}
+
+/// Exits if `filename` is an archive, which the single file operations would misread
+fn refuse_archive(filename: &str) {
+    if matches!(cryptile::inspect(filename), Ok(info) if info.archive) {
+        eprintln!("Error: {} is an archive, use `cryptile archive extract` to decrypt it", filename);
+        process::exit(1)
+    }
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    if let Err(m) = config::choose_keystore(&mut args) {
+        eprintln!("{}", m);
+        process::exit(1);
+    }
 
-    let mut config = match Config::parse(&args) {
+    let defaults = match Defaults::read() {
+        Ok(d) => d,
+        Err(m) => {
+            eprintln!("{}", m);
+            process::exit(1);
+        }
+    };
+
+    let mut config = match Config::parse(&args, &defaults) {
         Ok(c) => c,
         Err(m) => {
             eprintln!("{}", m);
@@ -70,9 +413,31 @@ fn main() {
         Operation::Encrypt => {
             let (key, filename, replace) = config.get_args();
 
-            if let Err(e) = cryptile::encrypt(filename, &key) {
-                let new_name = filename.to_owned() + cryptile::FILE_EXTENSION;
-                match e.kind() {
+            if config.recursive() {
+                match cryptile::encrypt_dir(filename, &key, config.dir_options()) {
+                    Ok(report) => print_report(&report, "encrypted"),
+                    Err(e) => {
+                        eprintln!("Error: {}", verify_error_message(&e));
+                        process::exit(1)
+                    }
+                }
+                return
+            }
+
+            if filename == STDIO {
+                let result = cryptile::encrypt_stream_with(&mut io::stdin().lock(),
+                    &mut BufWriter::new(io::stdout().lock()), &key, config.file_options());
+                if let Err(e) = result {
+                    eprintln!("{}", e);
+                    process::exit(1)
+                }
+                eprintln!("Successfully Encrypted the input");
+                return
+            }
+
+            let output = match cryptile::encrypt_with(filename, &key, config.file_options()) {
+                Ok(output) => output,
+                Err(e) => match e.kind() {
                     ErrorKind::NotFound => {
                         eprintln!("Error: File Not Found!");
                         process::exit(1);
@@ -82,13 +447,20 @@ fn main() {
                         process::exit(1);
                     }
                     _ => {
-                        cryptile::delete(&new_name);
+                        // Obfuscated outputs are removed by `encrypt_with` itself
+                        if !config.file_options().obfuscate {
+                            cryptile::delete(&(filename.to_owned() + cryptile::FILE_EXTENSION));
+                        }
                         eprintln!("{}", e);
                         process::exit(1)
                     }
                 }
+            };
+            if config.file_options().obfuscate {
+                eprintln!("Successfully Encrypted the file to {}", output);
+            } else {
+                eprintln!("Successfully Encrypted the file");
             }
-            println!("Successfully Encrypted the file");
             if replace {
                 cryptile::delete(filename);
             }
@@ -96,9 +468,33 @@ fn main() {
         Operation::Decrypt => {
             let (key, filename, replace) = config.get_args();
 
-            if let Err(e) = cryptile::decrypt(filename, &key) {
-                let new_name = filename.replace(cryptile::FILE_EXTENSION, "");
-                match e.kind() {
+            if config.recursive() {
+                match cryptile::decrypt_dir(filename, &key, config.dir_options()) {
+                    Ok(report) => print_report(&report, "decrypted"),
+                    Err(e) => {
+                        eprintln!("Error: {}", verify_error_message(&e));
+                        process::exit(1)
+                    }
+                }
+                return
+            }
+
+            if filename == STDIO {
+                let result = cryptile::decrypt_stream(
+                    &mut io::stdin().lock(), &mut BufWriter::new(io::stdout().lock()), &key);
+                if let Err(e) = result {
+                    eprintln!("Error: {}", verify_error_message(&e));
+                    process::exit(1)
+                }
+                eprintln!("Successfully Decrypted the input");
+                return
+            }
+
+            refuse_archive(filename);
+
+            let output = match cryptile::decrypt_with(filename, &key, config.file_options()) {
+                Ok(output) => output,
+                Err(e) => match e.kind() {
                     ErrorKind::Unsupported => {
                         eprintln!("Error: Unsupported File type");
                         process::exit(1)
@@ -119,45 +515,370 @@ fn main() {
                         eprintln!("Error: Unexpected End of File");
                         process::exit(1)
                     }
+                    ErrorKind::AlreadyExists => {
+                        eprintln!("Error: A file with the original name of the decrypted file already exists. \
+                                Use --no-metadata to decrypt it without restoring the name");
+                        process::exit(1)
+                    }
                     _ => {
-                        cryptile::delete(&new_name);
                         eprintln!("{}", e);
                         process::exit(1)
                     }
                 }
-            }
-            println!("Successfully Decrypted the file");
+            };
+            eprintln!("Successfully Decrypted the file to {}", output);
             if replace {
                 cryptile::delete(filename);
             }
         }
+        Operation::Verify => {
+            let (key, path, _) = config.get_args();
+
+            if Path::new(path).is_dir() {
+                let results = match cryptile::verify_dir(path, &key) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        eprintln!("Error: {}", verify_error_message(&e));
+                        process::exit(1)
+                    }
+                };
+
+                print_report(&results, "verified");
+            }
+            else {
+                if let Err(e) = cryptile::verify(path, &key) {
+                    eprintln!("Error: {}", verify_error_message(&e));
+                    process::exit(1)
+                }
+                match cryptile::inspect(path) {
+                    Ok(info) if !info.authenticated => {
+                        println!("Key is correct, but this file has no authentication data to verify");
+                    }
+                    _ => println!("Successfully Verified the file"),
+                }
+            }
+        }
+        Operation::Cat => {
+            let (key, filename, _) = config.get_args();
+
+            let stdout = io::stdout();
+            let guard_needed = stdout.is_terminal() && !config.force();
+            let mut writer = TextGuard::new(BufWriter::new(stdout.lock()), guard_needed);
+
+            let result = if filename == STDIO {
+                cryptile::decrypt_stream(&mut io::stdin().lock(), &mut writer, &key)
+            } else {
+                refuse_archive(filename);
+                cryptile::decrypt_to(filename, &key, &mut writer)
+            };
+
+            if let Err(e) = result {
+                if writer.refused {
+                    eprintln!("Error: The content looks binary. \
+                            Use --force to print it to the terminal anyway");
+                } else {
+                    eprintln!("Error: {}", verify_error_message(&e));
+                }
+                process::exit(1)
+            }
+        }
+        Operation::Edit => {
+            let (key, filename, _) = config.get_args();
+
+            match edit::edit(filename, &key) {
+                Ok(true) => eprintln!("Successfully Encrypted the edited file"),
+                Ok(false) => eprintln!("No changes made, the file was left untouched"),
+                Err(e) => {
+                    eprintln!("Error: {}", verify_error_message(&e));
+                    process::exit(1)
+                }
+            }
+        }
+        Operation::Exec => {
+            let (key, filename, _) = config.get_args();
+            let command = config.command().unwrap();
+            refuse_archive(filename);
+
+            match exec::exec(filename, &key, command) {
+                Ok(code) => process::exit(code),
+                Err(e) if e.kind() == ErrorKind::NotFound && Path::new(filename).exists() => {
+                    eprintln!("Error: Command `{}` not found", command[0]);
+                    process::exit(127)
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", verify_error_message(&e));
+                    process::exit(1)
+                }
+            }
+        }
+        Operation::Archive(command) => {
+            let (key, archive_name, _) = config.get_args();
+            let paths = config.paths();
+
+            let result = match command {
+                ArchiveCommand::Create => archive::create_with(paths, archive_name, &key, config.file_options()),
+                ArchiveCommand::List => archive::list(archive_name, &key),
+                ArchiveCommand::Extract => {
+                    let dirname = config.dir_options().output.unwrap_or(".");
+                    archive::extract(archive_name, &key, dirname, paths)
+                }
+                ArchiveCommand::Add => archive::add(archive_name, &key, paths),
+            };
+
+            let members = match result {
+                Ok(m) => m,
+                Err(e) => {
+                    eprintln!("Error: {}", verify_error_message(&e));
+                    process::exit(1)
+                }
+            };
+            match command {
+                ArchiveCommand::List => members.iter().for_each(print_member),
+                ArchiveCommand::Create => eprintln!("Successfully Created the archive with {} members", members.len()),
+                ArchiveCommand::Extract => eprintln!("Successfully Extracted {} members", members.len()),
+                ArchiveCommand::Add => eprintln!("Successfully Added {} members to the archive", members.len()),
+            }
+        }
+        Operation::Ls => {
+            let (key, dirname, _) = config.get_args();
+
+            let mut names = match list_names(dirname, &key) {
+                Ok(n) => n,
+                Err(e) => {
+                    eprintln!("Error: {}", verify_error_message(&e));
+                    process::exit(1)
+                }
+            };
+            names.sort();
+            let width = names.iter().map(|(file, _)| file.len()).max().unwrap_or(0);
+            for (file, name) in names {
+                println!("{:width$}  {}", file, name, width = width);
+            }
+        }
+        Operation::Keys(command) => {
+            let paths = config.paths().to_vec();
+            let pass = config.pass.take();
+            let bundle_name = config.file().map(|f| f.to_owned());
+            let source = config.source();
+            let conflict = config.on_conflict();
+            let allow_weak = config.allow_weak();
+            let keystore = config.keystore();
+
+            // The password of the bundle, asked for after unlocking the keystore
+            let exporting = command == KeysCommand::Export;
+            let bundle_pass = || {
+                let pass = match source.map(|s| s.read()) {
+                    None => get_pass_input(exporting),
+                    Some(result) => result?,
+                };
+                if exporting {
+                    check_strength(&pass, allow_weak);
+                }
+                Ok(pass)
+            };
+
+            let result = match command {
+                KeysCommand::List => {
+                    if keystore.keys().is_empty() {
+                        eprintln!("No Passwords saved.\n\
+                                Save one using `cryptile set -p` command");
+                    }
+                    let width = keystore.keys().iter().map(|k| k.identifier.len()).max().unwrap_or(0);
+                    for key in keystore.keys() {
+                        println!("{:width$}  {}", key.identifier,
+                            format_fingerprint(&cryptile::fingerprint(&key.key)), width = width);
+                    }
+                    Ok(())
+                }
+                KeysCommand::Remove => keystore.remove(paths[0])
+                    .map(|_| eprintln!("Successfully Removed the saved password {}", paths[0])),
+                KeysCommand::Rename => keystore.rename(paths[0], paths[1])
+                    .map(|_| eprintln!("Successfully Renamed {} to {}", paths[0], paths[1])),
+                KeysCommand::Fingerprint => {
+                    let key = match pass {
+                        Some(Pass::Saved { identifier }) => keystore.search(identifier.unwrap())
+                            .ok_or("No saved password with the given identifier found"),
+                        _ => keystore.master().ok_or("No master password set.\n\
+                                Set one using `cryptile set -m` command"),
+                    };
+                    key.map(|k| println!("{}", format_fingerprint(&cryptile::fingerprint(&k))))
+                }
+                KeysCommand::Export => {
+                    let bundle_name = bundle_name.unwrap();
+                    keystore.export(&paths, pass == Some(Pass::Master))
+                        .and_then(|bundle| bundle.write(&bundle_name, &bundle_pass()?))
+                        .map(|_| eprintln!("Successfully Exported the passwords to {}", bundle_name))
+                }
+                KeysCommand::Import => {
+                    let master = pass == Some(Pass::Master);
+                    bundle_pass()
+                        .and_then(|p| Bundle::read(&bundle_name.unwrap(), &p))
+                        .and_then(|bundle| {
+                            if bundle.has_master() && !master {
+                                eprintln!("The bundle holds a master password, use -m to import it as well");
+                            }
+                            // The keystore gets encrypted with the imported master password
+                            let master_pass = match bundle.master() {
+                                Some(m) if master && keystore.master() != Some(m) =>
+                                    Some(keystore::prompt("Enter the master password of the bundle", false)?),
+                                _ => None,
+                            };
+                            keystore.import(bundle, master_pass.as_deref(), conflict)
+                        })
+                        .map(|imported| imported.into_iter().for_each(print_imported))
+                }
+                KeysCommand::Backup => {
+                    let id = paths[0];
+                    keystore.search(id)
+                        .ok_or("No saved password with the given identifier found")
+                        .map(|key| {
+                            print_mnemonic(&mnemonic::encode(&key));
+                            eprintln!("Anyone with these words can decrypt the files encrypted with {}. \
+                                    Keep them safe, and restore it with `cryptile keys restore`", id);
+                        })
+                }
+                KeysCommand::Restore => {
+                    let input = get_input("Enter the words of the backup: ");
+                    // The numbers printed along with the words are left out
+                    let words: Vec<&str> = input.split_whitespace()
+                        .filter(|w| !w.trim_end_matches('.').bytes().all(|c| c.is_ascii_digit()))
+                        .collect();
+                    let key = match mnemonic::decode(&words) {
+                        Ok(key) => key,
+                        Err(e) => {
+                            eprintln!("Error: {}", e);
+                            process::exit(1)
+                        }
+                    };
+                    keystore.add(Key { key, identifier: paths[0].to_owned() })
+                        .map(|_| eprintln!("Successfully Restored the saved password {} ({})",
+                            paths[0], format_fingerprint(&cryptile::fingerprint(&key))))
+                }
+            };
+            if let Err(m) = result {
+                eprintln!("{}", m);
+                process::exit(1)
+            }
+        }
+        Operation::KeyfileGenerate => {
+            let path = config.file().unwrap();
+
+            if let Err(e) = cryptile::keyfile::generate(path) {
+                match e.kind() {
+                    ErrorKind::AlreadyExists => eprintln!("Error: {} already exists", path),
+                    _ => eprintln!("Error: {}", verify_error_message(&e)),
+                }
+                process::exit(1)
+            }
+            eprintln!("Successfully Generated the keyfile {}. \
+                    Keep it safe, the files encrypted with it can't be decrypted without it", path);
+        }
+        Operation::Generate(generate) => {
+            let (generated, bits) = match generate {
+                Generate::Password { length, charset } => (generate::password(length, charset),
+                    generate::entropy_bits(charset.chars().len(), length)),
+                Generate::Passphrase { words, separator } => (generate::passphrase(words, separator),
+                    generate::entropy_bits(generate::wordlist().len(), words)),
+            };
+            let generated = match generated {
+                Ok(g) => g,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    process::exit(1)
+                }
+            };
+            println!("{}", generated);
+            eprintln!("Entropy: {:.0} bits", bits);
+
+            if let Some(Pass::Saved { identifier: Some(id) }) = config.pass {
+                check_strength(&generated, config.allow_weak());
+                if let Err(m) = config.set_pass(generated, Some(id.to_owned())) {
+                    eprintln!("{}", m);
+                    process::exit(1)
+                }
+                eprintln!("Saved as {}", id);
+            }
+        }
+        Operation::Info => {
+            let filename = config.file().unwrap();
+
+            match cryptile::inspect(filename) {
+                Ok(info) => print_info(&info),
+                Err(e) => {
+                    match e.kind() {
+                        ErrorKind::Unsupported => eprintln!("Error: Not a cryptile encrypted file"),
+                        ErrorKind::NotFound => eprintln!("Error: File Not Found!"),
+                        ErrorKind::PermissionDenied => eprintln!("Error: Permission Denied"),
+                        ErrorKind::UnexpectedEof => eprintln!("Error: Unexpected End of File"),
+                        _ => eprintln!("{}", e),
+                    }
+                    process::exit(1)
+                }
+            }
+        }
+        Operation::Config(command) => {
+            let paths = config.paths();
+            match command {
+                ConfigCommand::Get if paths.is_empty() => {
+                    for key in Defaults::KEYS {
+                        if let Ok(Some(value)) = defaults.get(key) {
+                            println!("{} = {}", key, value);
+                        }
+                    }
+                }
+                ConfigCommand::Get => match defaults.get(paths[0]) {
+                    Ok(Some(value)) => println!("{}", value),
+                    Ok(None) => {
+                        eprintln!("{} is not set", paths[0]);
+                        process::exit(1)
+                    }
+                    Err(m) => {
+                        eprintln!("{}", m);
+                        process::exit(1)
+                    }
+                },
+                ConfigCommand::Set | ConfigCommand::Unset => {
+                    let mut changed = defaults.clone();
+                    if let Err(m) = changed.set(paths[0], paths.get(1).copied())
+                        .and_then(|_| changed.write())
+                    {
+                        eprintln!("{}", m);
+                        process::exit(1)
+                    }
+                }
+            }
+        }
         Operation::Set => {
+            let pass = match config.source().map(|s| s.read()) {
+                None => get_pass_input(true),
+                Some(Ok(pass)) => pass,
+                Some(Err(m)) => {
+                    eprintln!("{}", m);
+                    process::exit(1)
+                }
+            };
+            check_strength(&pass, config.allow_weak());
             match config.pass.as_ref().unwrap() {
                 Pass::Master => {
-                    let pass = get_pass_input();
-                    config.set_pass(pass, None);
+                    if let Err(m) = config.set_pass(pass, None) {
+                        eprintln!("{}", m);
+                        process::exit(1)
+                    }
                 }
                 Pass::Saved { identifier: _ } => {
-                    let pass = get_pass_input();
                     let id = get_input("Enter an identifier: ");
-                    config.set_pass(pass, Some(id));
+                    if let Err(m) = config.set_pass(pass, Some(id)) {
+                        eprintln!("{}", m);
+                        process::exit(1)
+                    }
                 }
                 _ => ()
             }
         }
     }
-}
-
-
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_pw_input() {
-        let p = get_pass_input();
-
-        println!("start->{}<-end", p);
+    if let Err(m) = config.save_keystore() {
+        eprintln!("{}", m);
+        process::exit(1);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,353 @@
+//! Metadata of the original file:
+//! its name, permissions, access and modification times and optionally
+//! its extended attributes, stored at the start of the encrypted payload
+//! so that decrypting gives back the file as it was.
+//!
+//! The record is preceded by its length (4) and laid out as:
+//! name length (2) | name | mode (4) | mtime (8) | mtime nanoseconds (4) |
+//! atime (8) | atime nanoseconds (4) | xattr count (2) |
+//! for each extended attribute: name length (2) | name | value length (4) | value
+//!
+//! All numbers are little endian. Times are seconds since the unix epoch.
+//! Being part of the payload, the record is encrypted and
+//! authenticated along with the content of the file.
+
+use std::fs::{self, File};
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Largest metadata record accepted when decrypting
+const MAX_RECORD_SIZE: u32 = 1_048_576;
+
+/// Permission bits stored and restored. The setuid, setgid and sticky bits
+/// are left out: restored, they would come from whoever made the file
+pub(crate) const PERMISSION_BITS: u32 = 0o777;
+
+/// Metadata of an original file, as stored in an encrypted file
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Metadata {
+    /// Name of the file, without its directory
+    pub name: String,
+    /// Unix permission bits, without the setuid, setgid and sticky bits
+    pub mode: u32,
+    pub modified: SystemTime,
+    pub accessed: SystemTime,
+    /// Extended attributes (name, value), empty if they weren't stored
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+impl Metadata {
+    /// Reads the metadata of a file, including its
+    /// extended attributes if `xattrs` is true
+    pub fn read(filename: &str, xattrs: bool) -> Result<Metadata, Error> {
+        let path = Path::new(filename);
+        let metadata = fs::metadata(path)?;
+        let name = path.file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "file name is not valid UTF-8"))?;
+
+        Ok(Metadata {
+            name: name.to_owned(),
+            mode: mode_of(&metadata),
+            modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+            accessed: metadata.accessed().unwrap_or(UNIX_EPOCH),
+            xattrs: if xattrs { read_xattrs(path)? } else { Vec::new() },
+        })
+    }
+
+    /// Restores the metadata (other than the name) on a file,
+    /// including the extended attributes if `xattrs` is true
+    pub fn apply(&self, filename: &str, xattrs: bool) -> Result<(), Error> {
+        let path = Path::new(filename);
+        if xattrs {
+            write_xattrs(path, &self.xattrs)?;
+        }
+
+        // Before the permissions, which may not allow writing anymore
+        let times = fs::FileTimes::new()
+            .set_modified(self.modified)
+            .set_accessed(self.accessed);
+        File::options().write(true).open(path)?.set_times(times)?;
+
+        set_mode(path, self.mode & PERMISSION_BITS)
+    }
+
+    /// The stored name, if it is safe to create in the directory of the
+    /// encrypted file: a plain file name, not a path
+    pub fn safe_name(&self) -> Option<&str> {
+        let name = self.name.as_str();
+        let unsafe_name = name.is_empty() || name == "." || name == ".."
+            || name.contains(['/', '\\', ':', '\0']);
+        if unsafe_name { None } else { Some(name) }
+    }
+
+    /// Serializes the record, preceded by its length
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut record = Vec::new();
+        put_bytes(&mut record, self.name.as_bytes(), 2);
+        record.extend_from_slice(&self.mode.to_le_bytes());
+        put_time(&mut record, self.modified);
+        put_time(&mut record, self.accessed);
+        record.extend_from_slice(&(self.xattrs.len() as u16).to_le_bytes());
+        for (name, value) in &self.xattrs {
+            put_bytes(&mut record, name.as_bytes(), 2);
+            put_bytes(&mut record, value, 4);
+        }
+
+        let mut bytes = (record.len() as u32).to_le_bytes().to_vec();
+        bytes.append(&mut record);
+        bytes
+    }
+
+    /// Parses a record (without its length)
+    fn parse(record: &[u8]) -> Result<Metadata, Error> {
+        let mut parser = RecordParser { bytes: record };
+        let name = parser.string(2)?;
+        let mode = u32::from_le_bytes(parser.array()?);
+        let modified = parser.time()?;
+        let accessed = parser.time()?;
+
+        let count = u16::from_le_bytes(parser.array()?);
+        let mut xattrs = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let name = parser.string(2)?;
+            xattrs.push((name, parser.bytes(4)?.to_vec()));
+        }
+
+        if !parser.bytes.is_empty() {
+            return Err(corrupted())
+        }
+        Ok(Metadata { name, mode, modified, accessed, xattrs })
+    }
+}
+
+fn corrupted() -> Error {
+    Error::new(ErrorKind::InvalidData, "corrupted metadata")
+}
+
+/// Reads a record (preceded by its length) from the start of a payload
+pub(crate) fn read_record<R: Read>(reader: &mut R) -> Result<Metadata, Error> {
+    // A payload ending before the record does is corrupted
+    let truncated = |e: Error| if e.kind() == ErrorKind::UnexpectedEof { corrupted() } else { e };
+    let mut length = [0u8; 4];
+    reader.read_exact(&mut length).map_err(truncated)?;
+    let length = u32::from_le_bytes(length);
+    if length > MAX_RECORD_SIZE {
+        return Err(corrupted())
+    }
+    let mut record = vec![0u8; length as usize];
+    reader.read_exact(&mut record).map_err(truncated)?;
+    Metadata::parse(&record)
+}
+
+/// Appends `bytes` preceded by their length, stored in `width` bytes
+fn put_bytes(record: &mut Vec<u8>, bytes: &[u8], width: usize) {
+    record.extend_from_slice(&(bytes.len() as u64).to_le_bytes()[..width]);
+    record.extend_from_slice(bytes);
+}
+
+fn put_time(record: &mut Vec<u8>, time: SystemTime) {
+    let (secs, nanos) = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(e) => {
+            // Before the epoch: a negative second count, plus the nanoseconds after it
+            let d = e.duration();
+            match d.subsec_nanos() {
+                0 => (-(d.as_secs() as i64), 0),
+                n => (-(d.as_secs() as i64) - 1, 1_000_000_000 - n),
+            }
+        }
+    };
+    record.extend_from_slice(&secs.to_le_bytes());
+    record.extend_from_slice(&nanos.to_le_bytes());
+}
+
+struct RecordParser<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> RecordParser<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() < n {
+            return Err(corrupted())
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    /// Bytes preceded by their length, stored in `width` bytes
+    fn bytes(&mut self, width: usize) -> Result<&'a [u8], Error> {
+        let mut length = [0u8; 8];
+        length[..width].copy_from_slice(self.take(width)?);
+        self.take(u64::from_le_bytes(length) as usize)
+    }
+
+    fn string(&mut self, width: usize) -> Result<String, Error> {
+        String::from_utf8(self.bytes(width)?.to_vec()).map_err(|_| corrupted())
+    }
+
+    fn time(&mut self) -> Result<SystemTime, Error> {
+        let secs = i64::from_le_bytes(self.array()?);
+        let nanos = u32::from_le_bytes(self.array()?);
+        if nanos >= 1_000_000_000 {
+            return Err(corrupted())
+        }
+        let time = if secs >= 0 {
+            UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64))
+        } else {
+            UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))
+        };
+        time.and_then(|t| t.checked_add(Duration::from_nanos(nanos as u64)))
+            .ok_or_else(corrupted)
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn mode_of(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & PERMISSION_BITS
+}
+
+#[cfg(not(unix))]
+pub(crate) fn mode_of(metadata: &fs::Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn set_mode(path: &Path, mode: u32) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_mode(path: &Path, mode: u32) -> Result<(), Error> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(unix)]
+fn read_xattrs(path: &Path) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let mut xattrs = Vec::new();
+    for name in xattr::list(path)? {
+        let name = name.into_string()
+            .map_err(|n| Error::new(ErrorKind::InvalidData,
+                format!("extended attribute name is not valid UTF-8: {:?}", n)))?;
+        if let Some(value) = xattr::get(path, &name)? {
+            xattrs.push((name, value));
+        }
+    }
+    Ok(xattrs)
+}
+
+#[cfg(not(unix))]
+fn read_xattrs(_: &Path) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    Err(Error::new(ErrorKind::Unsupported, "extended attributes aren't supported on this platform"))
+}
+
+#[cfg(unix)]
+fn write_xattrs(path: &Path, xattrs: &[(String, Vec<u8>)]) -> Result<(), Error> {
+    for (name, value) in xattrs {
+        xattr::set(path, name, value)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_xattrs(_: &Path, xattrs: &[(String, Vec<u8>)]) -> Result<(), Error> {
+    if xattrs.is_empty() {
+        return Ok(())
+    }
+    Err(Error::new(ErrorKind::Unsupported, "extended attributes aren't supported on this platform"))
+}
+
+/// Writer separating the metadata record at the start of a decrypted
+/// payload from the content of the file. The writer for the content is
+/// only opened (by calling `open` with the metadata) once the record has
+/// been read, so that where the content goes can depend on the metadata
+pub(crate) struct Splitter<W, O> {
+    open: Option<O>,
+    writer: Option<W>,
+    /// The record read so far, while it isn't complete
+    record: Option<Vec<u8>>,
+    metadata: Option<Metadata>,
+}
+
+impl<W: Write, O: FnOnce(Option<&Metadata>) -> Result<W, Error>> Splitter<W, O> {
+    /// `has_metadata` tells whether the payload starts with a metadata record
+    pub(crate) fn new(has_metadata: bool, open: O) -> Splitter<W, O> {
+        Splitter {
+            open: Some(open),
+            writer: None,
+            record: if has_metadata { Some(Vec::new()) } else { None },
+            metadata: None,
+        }
+    }
+
+    fn writer(&mut self) -> Result<&mut W, Error> {
+        if self.writer.is_none() {
+            let open = self.open.take().expect("the writer is only opened once");
+            self.writer = Some(open(self.metadata.as_ref())?);
+        }
+        Ok(self.writer.as_mut().unwrap())
+    }
+
+    /// Returns the metadata and the writer, once the whole payload has been written
+    pub(crate) fn finish(mut self) -> Result<(Option<Metadata>, W), Error> {
+        if self.record.is_some() {
+            return Err(corrupted())
+        }
+        self.writer()?.flush()?;
+        Ok((self.metadata, self.writer.unwrap()))
+    }
+}
+
+impl<W: Write, O: FnOnce(Option<&Metadata>) -> Result<W, Error>> Write for Splitter<W, O> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let total = buf.len();
+
+        while let Some(record) = self.record.as_mut() {
+            let wanted = if record.len() < 4 {
+                4
+            } else {
+                let length = u32::from_le_bytes(record[..4].try_into().unwrap());
+                if length > MAX_RECORD_SIZE {
+                    return Err(corrupted())
+                }
+                4 + length as usize
+            };
+
+            if record.len() >= 4 && record.len() == wanted {
+                self.metadata = Some(Metadata::parse(&record[4..])?);
+                self.record = None;
+                break
+            }
+            if buf.is_empty() {
+                return Ok(total)
+            }
+            let n = (wanted - record.len()).min(buf.len());
+            record.extend_from_slice(&buf[..n]);
+            buf = &buf[n..];
+        }
+
+        self.writer()?.write_all(buf)?;
+        Ok(total)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Paper backups of keys:
+//! a 32 byte key is written as 24 words of the BIP39 English wordlist,
+//! the same encoding as a 24 word BIP39 mnemonic.
+//!
+//! The 256 bits of the key are followed by the first 8 bits of its SHA-256,
+//! and the 264 bits are split into 24 words of 11 bits each, so that a
+//! mistyped word is very likely caught. Since the words are told apart by
+//! their first 4 letters, those are enough when reading a mnemonic back.
+
+use std::io::{Error, ErrorKind};
+use hmac_sha256::Hash;
+
+use crate::generate::wordlist;
+
+/// Number of words of a mnemonic
+pub const WORDS: usize = 24;
+
+/// Function to write a key as a mnemonic of `WORDS` words
+pub fn encode(key: &[u8; 32]) -> Vec<&'static str> {
+    let list = wordlist();
+    let mut bits = key.to_vec();
+    bits.push(Hash::hash(key)[0]);
+
+    (0..WORDS).map(|i| {
+        let index = (0..11).fold(0, |index, j| {
+            let bit = i * 11 + j;
+            index << 1 | (bits[bit / 8] >> (7 - bit % 8) & 1) as usize
+        });
+        list[index]
+    }).collect()
+}
+
+/// Function to read a key back from the words of its mnemonic.
+/// Words may be given in any case, or shortened to their first 4 letters
+///
+/// # Errors
+/// This function will return a `std::io::ErrorKind::InvalidData` error
+/// if there aren't `WORDS` words, one of them isn't in the wordlist,
+/// or the checksum doesn't match (a word is wrong or out of place).
+pub fn decode(words: &[&str]) -> Result<[u8; 32], Error> {
+    if words.len() != WORDS {
+        return Err(Error::new(ErrorKind::InvalidData,
+            format!("A backup is made of {} words, {} given", WORDS, words.len())));
+    }
+
+    let mut bits = [0u8; 33];
+    for (i, word) in words.iter().enumerate() {
+        let index = find(word).ok_or_else(|| Error::new(ErrorKind::InvalidData,
+            format!("Word {} ({}) is not in the wordlist", i + 1, word)))?;
+        for j in 0..11 {
+            let bit = i * 11 + j;
+            bits[bit / 8] |= ((index >> (10 - j) & 1) as u8) << (7 - bit % 8);
+        }
+    }
+
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bits[..32]);
+    if Hash::hash(&key)[0] != bits[32] {
+        return Err(Error::new(ErrorKind::InvalidData,
+            "The checksum doesn't match, a word is wrong or out of place"));
+    }
+    Ok(key)
+}
+
+/// The index of a word, or of the only word starting with its first 4 letters
+fn find(word: &str) -> Option<usize> {
+    let list = wordlist();
+    let word = word.to_lowercase();
+    if let Ok(index) = list.binary_search(&word.as_str()) {
+        return Some(index);
+    }
+    if word.len() < 4 {
+        return None;
+    }
+    let start = list.partition_point(|w| *w < word.as_str());
+    list.get(start).filter(|w| w.starts_with(&word)).map(|_| start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mnemonics() {
+        // Vectors of the BIP39 specification
+        let zeros = encode(&[0; 32]);
+        assert_eq!(zeros[..23], ["abandon"; 23]);
+        assert_eq!(zeros[23], "art");
+        let ones = encode(&[0xff; 32]);
+        assert_eq!(ones[..23], ["zoo"; 23]);
+        assert_eq!(ones[23], "vote");
+
+        let key = Hash::hash(b"cryptile");
+        let words = encode(&key);
+        assert_eq!(decode(&words).unwrap(), key);
+        let short: Vec<String> = words.iter().map(|w| w.get(..4).unwrap_or(w).to_uppercase()).collect();
+        let short: Vec<&str> = short.iter().map(|w| w.as_str()).collect();
+        assert_eq!(decode(&short).unwrap(), key);
+
+        let mut swapped = words.clone();
+        swapped.swap(0, 1);
+        assert!(swapped[0] == swapped[1] || decode(&swapped).is_err());
+        assert!(decode(&words[1..]).is_err());
+        let mut unknown = words.clone();
+        unknown[5] = "cryptile";
+        assert!(decode(&unknown).is_err());
+        assert!(decode(&["abandon"; 24]).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+//! Length-hiding padding:
+//! the payload is padded to a bucket size before being encrypted,
+//! so that the size of an encrypted file no longer tells the exact
+//! size of its content.
+//!
+//! The padding is zeros followed by the length of the unpadded payload (8),
+//! all inside the authenticated payload. It is added once the end of the
+//! input is reached, so the size doesn't need to be known in advance.
+
+use std::io::{self, Error, ErrorKind, Read, Write};
+
+/// Size of the trailer holding the length of the unpadded payload
+const TRAILER_SIZE: usize = 8;
+
+/// How the padded size is chosen
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Padding {
+    /// The next power of two.
+    /// Leaves at most log2(size) possible sizes, at up to 100% overhead
+    PowerOfTwo,
+    /// Padmé, which only keeps the top bits of the size.
+    /// Leaks O(log log size) bits, at no more than 12% overhead
+    Padme,
+}
+
+impl Padding {
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Padding::PowerOfTwo => 1,
+            Padding::Padme => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Padding> {
+        match id {
+            1 => Some(Padding::PowerOfTwo),
+            2 => Some(Padding::Padme),
+            _ => None,
+        }
+    }
+
+    /// The padded size for a payload of `size` bytes (trailer included)
+    pub fn padded_size(self, size: u64) -> u64 {
+        match self {
+            Padding::PowerOfTwo => size.checked_next_power_of_two().unwrap_or(size),
+            Padding::Padme => padme(size),
+        }
+    }
+}
+
+/// Rounds `size` up, keeping only its top log2(log2(size)) + 1 bits
+fn padme(size: u64) -> u64 {
+    if size < 2 {
+        return size
+    }
+    let e = 63 - size.leading_zeros() as u64;
+    let s = 64 - e.leading_zeros() as u64;
+    let mask = (1u64 << (e - s)) - 1;
+    size.checked_add(mask).map_or(size, |s| s & !mask)
+}
+
+/// Reader adding the padding at the end of the input
+pub(crate) struct Padder<R: Read> {
+    reader: R,
+    padding: Padding,
+    size: u64,
+    /// Zeros left to produce, then the trailer, once the input has ended
+    tail: Option<(u64, io::Cursor<[u8; TRAILER_SIZE]>)>,
+}
+
+impl<R: Read> Padder<R> {
+    pub(crate) fn new(reader: R, padding: Padding) -> Padder<R> {
+        Padder { reader, padding, size: 0, tail: None }
+    }
+}
+
+impl<R: Read> Read for Padder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+
+        let (zeros, trailer) = match self.tail.as_mut() {
+            Some(tail) => tail,
+            None => {
+                let n = self.reader.read(buf)?;
+                if n > 0 {
+                    self.size += n as u64;
+                    return Ok(n)
+                }
+                let padded = self.padding.padded_size(self.size + TRAILER_SIZE as u64);
+                let zeros = padded - self.size - TRAILER_SIZE as u64;
+                self.tail.insert((zeros, io::Cursor::new(self.size.to_le_bytes())))
+            }
+        };
+
+        if *zeros > 0 {
+            let n = (*zeros).min(buf.len() as u64) as usize;
+            buf[..n].fill(0);
+            *zeros -= n as u64;
+            return Ok(n)
+        }
+        trailer.read(buf)
+    }
+}
+
+fn corrupted() -> Error {
+    Error::new(ErrorKind::InvalidData, "corrupted padding")
+}
+
+/// Writer removing the padding from the end of the payload.
+/// Only the last bytes and a count of trailing zeros are held back,
+/// as they may turn out to be padding
+pub(crate) struct Unpadder<W: Write> {
+    writer: W,
+    written: u64,
+    /// Zeros received but not written yet
+    zeros: u64,
+    tail: [u8; TRAILER_SIZE],
+    tail_len: usize,
+}
+
+impl<W: Write> Unpadder<W> {
+    pub(crate) fn new(writer: W) -> Unpadder<W> {
+        Unpadder { writer, written: 0, zeros: 0, tail: [0; TRAILER_SIZE], tail_len: 0 }
+    }
+
+    fn write_zeros(&mut self, mut count: u64) -> io::Result<()> {
+        let zeros = [0u8; 4096];
+        while count > 0 {
+            let n = count.min(zeros.len() as u64) as usize;
+            self.writer.write_all(&zeros[..n])?;
+            count -= n as u64;
+        }
+        Ok(())
+    }
+
+    /// Passes on bytes known not to be the trailer, holding back trailing zeros
+    fn pass(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match bytes.iter().rposition(|b| *b != 0) {
+            Some(last) => {
+                let zeros = std::mem::take(&mut self.zeros);
+                self.write_zeros(zeros)?;
+                self.writer.write_all(&bytes[..=last])?;
+                self.written += zeros + last as u64 + 1;
+                self.zeros = (bytes.len() - last - 1) as u64;
+            }
+            None => self.zeros += bytes.len() as u64,
+        }
+        Ok(())
+    }
+
+    /// Writes the zeros belonging to the payload, once the whole payload was written
+    pub(crate) fn finish(&mut self) -> Result<(), Error> {
+        if self.tail_len < TRAILER_SIZE {
+            return Err(corrupted())
+        }
+        let size = u64::from_le_bytes(self.tail);
+        if size < self.written || size - self.written > self.zeros {
+            return Err(corrupted())
+        }
+        self.write_zeros(size - self.written)?;
+        self.written = size;
+        self.zeros = 0;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Write for Unpadder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let total = self.tail_len + buf.len();
+        if total <= TRAILER_SIZE {
+            self.tail[self.tail_len..total].copy_from_slice(buf);
+            self.tail_len = total;
+            return Ok(buf.len())
+        }
+
+        // The bytes leaving the tail, first from the tail then from `buf`
+        let leaving = total - TRAILER_SIZE;
+        let from_tail = leaving.min(self.tail_len);
+        let from_buf = leaving - from_tail;
+        let tail = self.tail;
+        self.pass(&tail[..from_tail])?;
+        self.pass(&buf[..from_buf])?;
+
+        let kept = self.tail_len - from_tail;
+        self.tail.copy_within(from_tail..self.tail_len, 0);
+        self.tail[kept..].copy_from_slice(&buf[from_buf..]);
+        self.tail_len = TRAILER_SIZE;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padded_sizes() {
+        assert_eq!(Padding::PowerOfTwo.padded_size(1000), 1024);
+        assert_eq!(Padding::PowerOfTwo.padded_size(1024), 1024);
+        assert_eq!(Padding::Padme.padded_size(9), 10);
+        assert_eq!(Padding::Padme.padded_size(1000), 1024);
+        assert_eq!(Padding::Padme.padded_size(1100), 1152);
+        assert_eq!(Padding::Padme.padded_size(1_000_000), 1_015_808);
+        for size in 2..100_000u64 {
+            let padded = Padding::Padme.padded_size(size);
+            assert!(padded >= size && padded - size <= size / 8);
+        }
+    }
+
+    #[test]
+    fn padding_round_trip() {
+        // Trailing zeros in the content must survive the removal of the padding
+        let contents: [&[u8]; 4] = [b"", b"\0\0\0", b"some content\0\0", &[0, 1, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0]];
+        for content in contents {
+            for padding in [Padding::PowerOfTwo, Padding::Padme] {
+                let mut padded = Vec::new();
+                Padder::new(content, padding).read_to_end(&mut padded).unwrap();
+                assert_eq!(padded.len() as u64, padding.padded_size(content.len() as u64 + 8));
+
+                let mut unpadded = Vec::new();
+                let mut unpadder = Unpadder::new(&mut unpadded);
+                for byte in &padded {
+                    unpadder.write_all(std::slice::from_ref(byte)).unwrap();
+                }
+                unpadder.finish().unwrap();
+                assert_eq!(unpadded, content);
+            }
+        }
+
+        let mut unpadder = Unpadder::new(Vec::new());
+        unpadder.write_all(b"no trailer").unwrap();
+        assert_eq!(unpadder.finish().err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+}
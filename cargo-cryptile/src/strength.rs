@@ -0,0 +1,459 @@
+//! Password strength estimation, in the way of zxcvbn:
+//! the password is split into the parts an attacker would guess separately
+//! (common passwords and words, keyboard rows, sequences, repeats, dates,
+//! or else every character), and the number of guesses needed to find it
+//! is that of the split needing the fewest.
+//!
+//! The key of a file is a single SHA-256 of its password, so crack times
+//! are those of an offline attack on a fast hash.
+
+use std::collections::HashMap;
+
+/// Guesses per second of an offline attack on a fast hash, on a few GPUs
+pub const OFFLINE_FAST_HASH_RATE: f64 = 1e10;
+
+/// Passwords found faster than this, in seconds, by an offline attack
+/// at `OFFLINE_FAST_HASH_RATE` are too guessable for a new password
+pub const MIN_CRACK_SECONDS: f64 = 3600.0;
+
+/// Characters looked at, the rest of longer passwords is guessed character by character
+const MAX_LENGTH: usize = 100;
+
+/// Guesses per character of parts not matching any pattern
+const BRUTEFORCE_CARDINALITY: f64 = 10.0;
+
+/// Makes passwords of many small parts cost more than the parts alone
+const MIN_GUESSES_PER_PART: f64 = 10_000.0;
+
+/// Passwords and words tried first, the most common first
+const COMMON: &[&str] = &[
+    "123456", "password", "12345678", "qwerty", "123456789", "12345", "1234", "111111",
+    "1234567", "dragon", "123123", "baseball", "abc123", "football", "monkey", "letmein",
+    "696969", "shadow", "master", "666666", "qwertyuiop", "123321", "mustang", "1234567890",
+    "michael", "654321", "superman", "1qaz2wsx", "7777777", "121212", "000000", "qazwsx",
+    "123qwe", "killer", "trustno1", "jordan", "jennifer", "zxcvbnm", "asdfgh", "hunter",
+    "buster", "soccer", "harley", "batman", "andrew", "tigger", "sunshine", "iloveyou",
+    "2000", "charlie", "robert", "thomas", "hockey", "ranger", "daniel", "starwars",
+    "klaster", "112233", "george", "computer", "michelle", "jessica", "pepper", "1111",
+    "zxcvbn", "555555", "11111111", "131313", "freedom", "777777", "pass", "maggie",
+    "159753", "aaaaaa", "ginger", "princess", "joshua", "cheese", "amanda", "summer",
+    "love", "ashley", "nicole", "chelsea", "biteme", "matthew", "access", "yankees",
+    "987654321", "dallas", "austin", "thunder", "taylor", "matrix", "minecraft", "welcome",
+    "admin", "login", "passw0rd", "secret", "hello", "flower", "whatever", "qwerty123",
+    "password1", "changeme", "default", "guest", "root", "test", "dragon1", "monkey1",
+    "cryptile", "letmein1", "abcdef", "abcd", "azerty", "asdf", "qwer", "zaq12wsx",
+    "money", "orange", "banana", "apple", "purple", "silver", "golden", "diamond",
+    "angel", "lovely", "family", "friend", "forever", "happy", "secure", "private",
+    "house", "horse", "battery", "staple", "correct", "winter", "spring", "autumn",
+    "monday", "friday", "sunday", "january", "december", "google", "facebook", "internet",
+    "samsung", "apple123", "pokemon", "naruto", "soccer1", "football1", "baseball1", "hello123",
+];
+
+const KEYBOARD_ROWS: [&str; 4] = ["`1234567890-=", "qwertyuiop[]\\", "asdfghjkl;'", "zxcvbnm,./"];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Pattern {
+    /// The whole password is a common one
+    Common,
+    Dictionary,
+    Keyboard,
+    Sequence,
+    Repeat,
+    Date,
+    Bruteforce,
+}
+
+struct Match {
+    start: usize,
+    /// Exclusive
+    end: usize,
+    guesses: f64,
+    pattern: Pattern,
+}
+
+/// How guessable a password is
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Strength {
+    /// Estimated number of guesses needed to find the password
+    pub guesses: f64,
+    /// From 0 (too guessable) to 4 (very unguessable)
+    pub score: u8,
+    /// What makes a weak password guessable
+    pub warning: Option<&'static str>,
+}
+
+impl Strength {
+    /// Seconds needed to find the password, on average, at `rate` guesses per second
+    pub fn crack_seconds(&self, rate: f64) -> f64 {
+        self.guesses / 2.0 / rate
+    }
+
+    /// Whether the password is too guessable to be used as a new one
+    pub fn is_weak(&self) -> bool {
+        self.crack_seconds(OFFLINE_FAST_HASH_RATE) < MIN_CRACK_SECONDS
+    }
+}
+
+/// Function to estimate how guessable a password is
+pub fn estimate(password: &str) -> Strength {
+    let chars: Vec<char> = password.chars().collect();
+    let (checked, rest) = chars.split_at(chars.len().min(MAX_LENGTH));
+
+    let (guesses, parts) = most_guessable_split(checked);
+    let guesses = guesses * BRUTEFORCE_CARDINALITY.powi(rest.len() as i32);
+    let score = match guesses {
+        g if g < 1e3 + 5.0 => 0,
+        g if g < 1e6 + 5.0 => 1,
+        g if g < 1e8 + 5.0 => 2,
+        g if g < 1e10 + 5.0 => 3,
+        _ => 4,
+    };
+
+    let mut strength = Strength { guesses, score, warning: None };
+    if !strength.is_weak() || chars.is_empty() {
+        return strength;
+    }
+    // About the part covering most of the password
+    let longest = parts.iter()
+        .filter(|m| m.pattern != Pattern::Bruteforce)
+        .max_by_key(|m| m.end - m.start);
+    strength.warning = Some(match longest.map(|m| m.pattern) {
+        Some(Pattern::Common) => "This is a very common password",
+        Some(Pattern::Dictionary) => "Common words and passwords are easy to guess, even with symbols for letters",
+        Some(Pattern::Keyboard) => "Straight rows of keys are easy to guess",
+        Some(Pattern::Sequence) => "Sequences like abc or 6543 are easy to guess",
+        Some(Pattern::Repeat) => "Repeats like aaa or abcabc are easy to guess",
+        Some(Pattern::Date) => "Dates and years are easy to guess",
+        Some(Pattern::Bruteforce) | None => "Short passwords are easy to guess, add more words or characters",
+    });
+    strength
+}
+
+/// Function to tell a duration in words, as `3 hours` or `centuries`
+pub fn format_duration(seconds: f64) -> String {
+    const UNITS: [(f64, &str); 6] = [
+        (1.0, "second"),
+        (60.0, "minute"),
+        (3600.0, "hour"),
+        (86_400.0, "day"),
+        (86_400.0 * 31.0, "month"),
+        (86_400.0 * 365.0, "year"),
+    ];
+    if seconds < 1.0 {
+        return "less than a second".to_owned();
+    }
+    if seconds >= 86_400.0 * 365.0 * 100.0 {
+        return "centuries".to_owned();
+    }
+    let (unit, name) = UNITS.iter().rev().find(|(unit, _)| seconds >= *unit).unwrap();
+    let count = (seconds / unit).round();
+    format!("{} {}{}", count, name, if count == 1.0 { "" } else { "s" })
+}
+
+/// The fewest guesses over the splits of `chars` into matches and bruteforced parts,
+/// along with the parts of that split
+fn most_guessable_split(chars: &[char]) -> (f64, Vec<Match>) {
+    let n = chars.len();
+    if n == 0 {
+        return (1.0, Vec::new());
+    }
+
+    let mut matches = find_matches(chars);
+    for start in 0..n {
+        for end in start + 1..=n {
+            let len = (end - start) as i32;
+            let guesses = BRUTEFORCE_CARDINALITY.powi(len).max(if len == 1 { 11.0 } else { 51.0 });
+            matches.push(Match { start, end, guesses, pattern: Pattern::Bruteforce });
+        }
+    }
+    let mut ending: Vec<Vec<usize>> = (0..=n).map(|_| Vec::new()).collect();
+    for (i, m) in matches.iter().enumerate() {
+        ending[m.end].push(i);
+    }
+
+    // best[end][parts]: fewest guesses of the first `end` characters
+    // split into `parts` parts, and the last of these parts
+    let mut best: Vec<Vec<Option<(f64, usize)>>> = vec![vec![None; n + 1]; n + 1];
+    best[0][0] = Some((1.0, usize::MAX));
+    for end in 1..=n {
+        for &i in &ending[end] {
+            let m = &matches[i];
+            for parts in 1..=end {
+                let Some((before, _)) = best[m.start][parts - 1] else { continue };
+                let guesses = before * m.guesses;
+                if best[end][parts].is_none_or(|(g, _)| guesses < g) {
+                    best[end][parts] = Some((guesses, i));
+                }
+            }
+        }
+    }
+
+    let mut factorial = 1.0;
+    let mut fewest = (f64::INFINITY, 0);
+    for (parts, split) in best[n].iter().enumerate().skip(1) {
+        factorial *= parts as f64;
+        if let Some((product, _)) = *split {
+            let guesses = factorial * product + MIN_GUESSES_PER_PART.powi(parts as i32 - 1);
+            if guesses < fewest.0 {
+                fewest = (guesses, parts);
+            }
+        }
+    }
+
+    let (guesses, mut parts) = fewest;
+    let mut end = n;
+    let mut split = Vec::new();
+    while parts > 0 {
+        let (_, i) = best[end][parts].unwrap();
+        let m = &matches[i];
+        end = m.start;
+        parts -= 1;
+        split.push(Match { start: m.start, end: m.end, guesses: m.guesses, pattern: m.pattern });
+    }
+    split.reverse();
+    (guesses, split)
+}
+
+fn find_matches(chars: &[char]) -> Vec<Match> {
+    let lower: Vec<char> = chars.iter().map(|c| c.to_lowercase().next().unwrap_or(*c)).collect();
+    let mut matches = Vec::new();
+    dictionary_matches(chars, &lower, &mut matches);
+    keyboard_matches(&lower, &mut matches);
+    sequence_matches(&lower, &mut matches);
+    repeat_matches(chars, &mut matches);
+    date_matches(chars, &mut matches);
+    matches
+}
+
+/// The letter a symbol or digit commonly stands for
+fn unleet(c: char) -> char {
+    match c {
+        '4' | '@' => 'a',
+        '3' => 'e',
+        '1' | '!' => 'i',
+        '0' => 'o',
+        '$' | '5' => 's',
+        '7' => 't',
+        c => c,
+    }
+}
+
+fn binomial(n: usize, k: usize) -> f64 {
+    (0..k).fold(1.0, |acc, i| acc * (n - i) as f64 / (i + 1) as f64)
+}
+
+/// Guesses needed to find which letters of a word are uppercase
+fn uppercase_variations(word: &[char]) -> f64 {
+    let upper = word.iter().filter(|c| c.is_uppercase()).count();
+    let lower = word.iter().filter(|c| c.is_lowercase()).count();
+    let first_or_last = word.first().is_some_and(|c| c.is_uppercase())
+        || word.last().is_some_and(|c| c.is_uppercase());
+    if upper == 0 {
+        1.0
+    } else if lower == 0 || (upper == 1 && first_or_last) {
+        2.0
+    } else {
+        (1..=upper.min(lower)).map(|i| binomial(upper + lower, i)).sum()
+    }
+}
+
+fn dictionary_matches(chars: &[char], lower: &[char], matches: &mut Vec<Match>) {
+    let ranks: HashMap<String, usize> = COMMON.iter().enumerate().map(|(i, w)| (w.to_string(), i + 1)).collect();
+    let unleeted: Vec<char> = lower.iter().map(|c| unleet(*c)).collect();
+    let n = chars.len();
+
+    for start in 0..n {
+        for end in start + 3..=n {
+            let leet = unleeted[start..end] != lower[start..end];
+            let variants = [(&lower[start..end], 1.0), (&unleeted[start..end], 2.0)];
+            for (word, extra) in variants.into_iter().take(if leet { 2 } else { 1 }) {
+                let forwards: String = word.iter().collect();
+                let backwards: String = word.iter().rev().collect();
+                let rank = match (ranks.get(&forwards), ranks.get(&backwards)) {
+                    (Some(rank), _) => *rank as f64,
+                    (None, Some(rank)) => *rank as f64 * 2.0,
+                    (None, None) => continue,
+                };
+                let pattern = if start == 0 && end == n { Pattern::Common } else { Pattern::Dictionary };
+                let guesses = rank * extra * uppercase_variations(&chars[start..end]);
+                matches.push(Match { start, end, guesses, pattern });
+            }
+        }
+    }
+}
+
+fn keyboard_matches(lower: &[char], matches: &mut Vec<Match>) {
+    // Keys to start from, times the directions to go in
+    const START_GUESSES: f64 = 47.0 * 4.6;
+    let rows: Vec<Vec<char>> = KEYBOARD_ROWS.iter()
+        .flat_map(|row| [row.chars().collect(), row.chars().rev().collect()])
+        .collect();
+
+    for start in 0..lower.len() {
+        for row in &rows {
+            let Some(pos) = row.iter().position(|c| *c == lower[start]) else { continue };
+            let len = lower[start..].iter().zip(&row[pos..]).take_while(|(a, b)| a == b).count();
+            if len >= 4 {
+                let guesses = START_GUESSES * (len - 1) as f64;
+                matches.push(Match { start, end: start + len, guesses, pattern: Pattern::Keyboard });
+            }
+        }
+    }
+}
+
+fn sequence_matches(lower: &[char], matches: &mut Vec<Match>) {
+    let same_class = |a: char, b: char| {
+        (a.is_ascii_digit() && b.is_ascii_digit()) || (a.is_ascii_lowercase() && b.is_ascii_lowercase())
+    };
+    let mut start = 0;
+    while start + 2 < lower.len() {
+        let delta = lower[start + 1] as i32 - lower[start] as i32;
+        let mut end = start + 1;
+        while end < lower.len() && (delta == 1 || delta == -1) && same_class(lower[start], lower[end])
+            && lower[end] as i32 - lower[end - 1] as i32 == delta
+        {
+            end += 1;
+        }
+        if end - start >= 3 {
+            let base = match lower[start] {
+                'a' | 'z' | '0' | '1' | '9' => 4.0,
+                c if c.is_ascii_digit() => 10.0,
+                _ => 26.0,
+            };
+            let direction = if delta < 0 { 2.0 } else { 1.0 };
+            let guesses = base * (end - start) as f64 * direction;
+            matches.push(Match { start, end, guesses, pattern: Pattern::Sequence });
+            start = end - 1;
+        } else {
+            start += 1;
+        }
+    }
+}
+
+fn repeat_matches(chars: &[char], matches: &mut Vec<Match>) {
+    let n = chars.len();
+    for start in 0..n {
+        // The smallest repeated block, as larger ones repeat it too
+        for block in 1..=(n - start) / 2 {
+            // Only from the first of the repeated blocks
+            if start >= block && chars[start - block..start] == chars[start..start + block] {
+                continue;
+            }
+            let unit = &chars[start..start + block];
+            let count = chars[start..].chunks_exact(block).take_while(|c| *c == unit).count();
+            if count < 2 || (block == 1 && count < 3) {
+                continue;
+            }
+            let unit_guesses = if block == 1 { BRUTEFORCE_CARDINALITY } else { most_guessable_split(unit).0 };
+            let guesses = unit_guesses * count as f64;
+            matches.push(Match { start, end: start + block * count, guesses, pattern: Pattern::Repeat });
+            break;
+        }
+    }
+}
+
+fn date_matches(chars: &[char], matches: &mut Vec<Match>) {
+    const YEARS: f64 = 150.0;
+    const DAYS: f64 = 365.0;
+    let number = |digits: &[char]| digits.iter().fold(0, |n, d| n * 10 + d.to_digit(10).unwrap());
+    let is_year = |year| (1900..2050).contains(&year);
+    let is_day_month = |day, month| (1..=31).contains(&day) && (1..=12).contains(&month);
+
+    for start in 0..chars.len() {
+        for len in [4, 6, 8] {
+            let Some(digits) = chars.get(start..start + len) else { break };
+            if !digits.iter().all(|c| c.is_ascii_digit()) {
+                break;
+            }
+            let parts = |split: &[usize]| {
+                let mut at = 0;
+                split.iter().map(|len| {
+                    at += len;
+                    number(&digits[at - len..at])
+                }).collect::<Vec<_>>()
+            };
+            let guesses = match len {
+                4 if is_year(number(digits)) => YEARS,
+                6 => {
+                    let p = parts(&[2, 2, 2]);
+                    if is_day_month(p[0], p[1]) || is_day_month(p[1], p[0]) || is_day_month(p[2], p[1]) {
+                        DAYS * 100.0
+                    } else {
+                        continue;
+                    }
+                }
+                8 => {
+                    let (a, b) = (parts(&[2, 2, 4]), parts(&[4, 2, 2]));
+                    if (is_year(a[2]) && (is_day_month(a[0], a[1]) || is_day_month(a[1], a[0])))
+                        || (is_year(b[0]) && is_day_month(b[2], b[1]))
+                    {
+                        DAYS * YEARS
+                    } else {
+                        continue;
+                    }
+                }
+                _ => continue,
+            };
+            matches.push(Match { start, end: start + len, guesses, pattern: Pattern::Date });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weak_passwords() {
+        let weak = [
+            ("password", "This is a very common password"),
+            ("P@ssw0rd", "This is a very common password"),
+            ("drowssap", "This is a very common password"),
+            ("qwertyuiop", "This is a very common password"),
+            ("asdfghjkl;", "Straight rows of keys are easy to guess"),
+            ("abcdefghij", "Sequences like abc or 6543 are easy to guess"),
+            ("zzzzzzzzzzzz", "Repeats like aaa or abcabc are easy to guess"),
+            ("19900512", "Dates and years are easy to guess"),
+            ("kx7", "Short passwords are easy to guess, add more words or characters"),
+        ];
+        for (password, warning) in weak {
+            let strength = estimate(password);
+            assert!(strength.is_weak(), "{} scored {}", password, strength.score);
+            assert_eq!(strength.warning, Some(warning), "{}", password);
+        }
+    }
+
+    #[test]
+    fn strong_passwords() {
+        for password in ["correct horse battery staple", "x7#Kq!9vLm2$Rt", "gloomy-otter-spins-a-kettle"] {
+            let strength = estimate(password);
+            assert_eq!(strength.score, 4, "{}", password);
+            assert_eq!(strength.warning, None);
+        }
+        // Longer than what is looked at
+        assert_eq!(estimate(&"ab".repeat(80)).score, 4);
+    }
+
+    #[test]
+    fn refused_below_min_crack_time() {
+        // Shown as cracked in less than a second
+        for password in ["kx7pq2m", "kx7pq2m9", "Tr0ub4dor", "k8#vQ2z"] {
+            let strength = estimate(password);
+            assert!(strength.is_weak(), "{} took {}", password, strength.crack_seconds(OFFLINE_FAST_HASH_RATE));
+            assert!(strength.warning.is_some(), "{}", password);
+        }
+        assert_eq!(format_duration(estimate("kx7pq2m").crack_seconds(OFFLINE_FAST_HASH_RATE)), "less than a second");
+        let strength = estimate("x7#Kq!9vLm2$Rt");
+        assert!(strength.crack_seconds(OFFLINE_FAST_HASH_RATE) >= MIN_CRACK_SECONDS);
+        assert!(!strength.is_weak());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(0.5), "less than a second");
+        assert_eq!(format_duration(1.0), "1 second");
+        assert_eq!(format_duration(7200.0), "2 hours");
+        assert_eq!(format_duration(86_400.0 * 400.0), "1 year");
+        assert_eq!(format_duration(1e12), "centuries");
+    }
+}